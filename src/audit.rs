@@ -0,0 +1,448 @@
+use crate::crypto::own_key_fingerprint;
+use crate::error::DmError;
+use crate::vault::Vault;
+use hmac::{Hmac, Mac};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::MetadataExt;
+
+/// A recorded vault operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub created_at: String,
+    pub operation: String,
+    /// Secret name, file path or key hash the operation was applied to
+    pub target: String,
+    pub os_user: String,
+    /// Key of the user who did it, if their secret key was available
+    pub key_fingerprint: Option<String>,
+}
+
+/// Outcome of [`Vault::verify_audit_log`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditVerification {
+    pub checked: usize,
+    /// Entries whose hash does not match their content or the previous entry,
+    /// because they or an entry before them were edited or removed
+    pub broken: Vec<i64>,
+    /// Whether the keyed record of the newest entry and the number of entries
+    /// matches the log. False when entries were removed from its end.
+    pub head_valid: bool,
+}
+
+impl AuditVerification {
+    pub fn is_valid(&self) -> bool {
+        self.broken.is_empty() && self.head_valid
+    }
+}
+
+/// Number of entries and hash of the newest one, keyed so that the log cannot be
+/// truncated unnoticed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AuditHead {
+    entries: i64,
+    /// Entries written before the log was keyed, chained with plain SHA-256
+    legacy_entries: i64,
+    hash: String,
+}
+
+/// The stored [`AuditHead`], checked against its MAC.
+enum HeadRecord {
+    Valid(AuditHead),
+    Forged,
+    Missing,
+}
+
+impl AuditHead {
+    fn mac(&self, key: &[u8]) -> String {
+        keyed_hash(
+            key,
+            &[
+                &self.entries.to_string(),
+                &self.legacy_entries.to_string(),
+                &self.hash,
+            ],
+        )
+    }
+}
+
+impl Vault {
+    /// Lists recorded operations, oldest first. With `limit` only the newest ones.
+    pub fn audit_log(&self, limit: Option<usize>) -> Result<Vec<AuditEntry>, DmError> {
+        Self::get_audit_log(&self.conn, limit)
+    }
+
+    /// Recomputes the hash chain of the audit log. It is keyed with a secret only
+    /// recipients can decrypt, so nobody else can rebuild it after a change.
+    pub fn verify_audit_log(&self) -> Result<AuditVerification, DmError> {
+        Self::check_audit_chain(&self.conn, &self.audit_key()?)
+    }
+
+    /// Key of the audit log chain, derived from the content hash key.
+    pub(crate) fn audit_key(&self) -> Result<Vec<u8>, DmError> {
+        let key = keyed_hash(&self.content_hash_key()?, &["audit log"]);
+        Ok(key.into_bytes())
+    }
+
+    fn get_audit_log(conn: &Connection, limit: Option<usize>) -> Result<Vec<AuditEntry>, DmError> {
+        let limit = limit.map_or(-1, |limit| limit as i64);
+        let mut stmt = conn.prepare(
+            "SELECT id, created_at, operation, target, os_user, key_fingerprint FROM (
+                SELECT * FROM audit_log ORDER BY id DESC LIMIT ?1
+             ) ORDER BY id",
+        )?;
+        let entry_iter = stmt.query_map(rusqlite::params![limit], |row| {
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                created_at: row.get(1)?,
+                operation: row.get(2)?,
+                target: row.get(3)?,
+                os_user: row.get(4)?,
+                key_fingerprint: row.get(5)?,
+            })
+        })?;
+
+        let mut entries = Vec::new();
+        for entry in entry_iter {
+            entries.push(entry?);
+        }
+        Ok(entries)
+    }
+
+    fn check_audit_chain(conn: &Connection, key: &[u8]) -> Result<AuditVerification, DmError> {
+        let head = Self::audit_head(conn, key)?;
+        // A log that was never sealed by a keyed write holds only legacy entries
+        let legacy_entries = match &head {
+            HeadRecord::Valid(head) => head.legacy_entries,
+            _ => i64::MAX,
+        };
+
+        let mut stmt = conn.prepare(
+            "SELECT id, created_at, operation, target, os_user, key_fingerprint, prev_hash, hash
+             FROM audit_log ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+
+        let mut verification = AuditVerification {
+            checked: 0,
+            broken: Vec::new(),
+            head_valid: false,
+        };
+        let mut expected_prev = String::new();
+        while let Some(row) = rows.next()? {
+            let entry = AuditEntry {
+                id: row.get(0)?,
+                created_at: row.get(1)?,
+                operation: row.get(2)?,
+                target: row.get(3)?,
+                os_user: row.get(4)?,
+                key_fingerprint: row.get(5)?,
+            };
+            let prev_hash: String = row.get(6)?;
+            let hash: String = row.get(7)?;
+
+            let expected = if (verification.checked as i64) < legacy_entries {
+                legacy_entry_hash(&prev_hash, &entry)
+            } else {
+                entry_hash(key, &prev_hash, &entry)
+            };
+            if prev_hash != expected_prev || hash != expected {
+                verification.broken.push(entry.id);
+            }
+            expected_prev = hash;
+            verification.checked += 1;
+        }
+
+        verification.head_valid = match head {
+            HeadRecord::Valid(head) => {
+                head.entries == verification.checked as i64 && head.hash == expected_prev
+            }
+            HeadRecord::Forged => false,
+            HeadRecord::Missing => verification.checked == 0,
+        };
+        Ok(verification)
+    }
+
+    fn audit_head(conn: &Connection, key: &[u8]) -> Result<HeadRecord, DmError> {
+        let row: Option<(AuditHead, String)> = conn
+            .query_row(
+                "SELECT entries, legacy_entries, hash, mac FROM audit_head WHERE id = 1",
+                [],
+                |row| {
+                    let head = AuditHead {
+                        entries: row.get(0)?,
+                        legacy_entries: row.get(1)?,
+                        hash: row.get(2)?,
+                    };
+                    Ok((head, row.get(3)?))
+                },
+            )
+            .optional()?;
+        Ok(match row {
+            Some((head, mac)) if mac == head.mac(key) => HeadRecord::Valid(head),
+            Some(_) => HeadRecord::Forged,
+            None => HeadRecord::Missing,
+        })
+    }
+
+    /// Appends an entry to the audit log, chained with `key` from [`Vault::audit_key`].
+    /// Mutations call this with their transaction, so the entry is only kept if the
+    /// operation is.
+    pub(crate) fn audit(
+        conn: &Connection,
+        key: &[u8],
+        operation: &str,
+        target: &str,
+    ) -> Result<(), DmError> {
+        let prev_hash: String = conn.query_row(
+            "SELECT COALESCE((SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1), '')",
+            [],
+            |row| row.get(0),
+        )?;
+        let entry = AuditEntry {
+            id: 0,
+            created_at: conn.query_row("SELECT datetime('now')", [], |row| row.get(0))?,
+            operation: operation.to_string(),
+            target: target.to_string(),
+            os_user: os_user(),
+            key_fingerprint: own_key_fingerprint(&Self::get_recipients(conn)?),
+        };
+        let hash = entry_hash(key, &prev_hash, &entry);
+        conn.execute(
+            "INSERT INTO audit_log
+                (created_at, operation, target, os_user, key_fingerprint, prev_hash, hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                &entry.created_at,
+                &entry.operation,
+                &entry.target,
+                &entry.os_user,
+                &entry.key_fingerprint,
+                &prev_hash,
+                &hash,
+            ],
+        )?;
+
+        let head = match Self::audit_head(conn, key)? {
+            HeadRecord::Valid(head) => AuditHead {
+                entries: head.entries + 1,
+                legacy_entries: head.legacy_entries,
+                hash,
+            },
+            // A forged head is left as it is, so the change stays visible
+            HeadRecord::Forged => return Ok(()),
+            // Entries of logs written before they were keyed are kept as they are
+            HeadRecord::Missing => {
+                let entries: i64 =
+                    conn.query_row("SELECT COUNT(*) FROM audit_log", [], |row| row.get(0))?;
+                AuditHead {
+                    entries,
+                    legacy_entries: entries - 1,
+                    hash,
+                }
+            }
+        };
+        conn.execute(
+            "INSERT OR REPLACE INTO audit_head (id, entries, legacy_entries, hash, mac)
+             VALUES (1, ?1, ?2, ?3, ?4)",
+            rusqlite::params![head.entries, head.legacy_entries, &head.hash, head.mac(key)],
+        )?;
+        Ok(())
+    }
+}
+
+/// Fields of an entry chained to the hash of the entry before it. The id is left
+/// out, removed entries are detected through the chain instead.
+fn entry_fields<'a>(prev_hash: &'a str, entry: &'a AuditEntry) -> [&'a str; 6] {
+    [
+        prev_hash,
+        &entry.created_at,
+        &entry.operation,
+        &entry.target,
+        &entry.os_user,
+        entry.key_fingerprint.as_deref().unwrap_or(""),
+    ]
+}
+
+fn entry_hash(key: &[u8], prev_hash: &str, entry: &AuditEntry) -> String {
+    keyed_hash(key, &entry_fields(prev_hash, entry))
+}
+
+/// Unkeyed hash of entries written by older versions.
+fn legacy_entry_hash(prev_hash: &str, entry: &AuditEntry) -> String {
+    let mut hasher = Sha256::new();
+    for field in entry_fields(prev_hash, entry) {
+        hasher.update(field.as_bytes());
+        hasher.update([0]);
+    }
+    hex(&hasher.finalize())
+}
+
+/// Hex encoded HMAC-SHA256 of NUL-terminated `fields`.
+fn keyed_hash(key: &[u8], fields: &[&str]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for field in fields {
+        mac.update(field.as_bytes());
+        mac.update(&[0]);
+    }
+    hex(&mac.finalize().into_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn os_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| match std::fs::metadata("/proc/self") {
+            Ok(metadata) => format!("uid {}", metadata.uid()),
+            Err(_) => "unknown".to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::migrate;
+
+    const KEY: &[u8] = b"audit key";
+
+    fn log_with(targets: &[&str]) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        for target in targets {
+            Vault::audit(&conn, KEY, "secret_show", target).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn test_audit_log_chain() {
+        let conn = log_with(&["one", "two", "three"]);
+
+        // The log is append-only through SQL
+        assert!(conn
+            .execute("DELETE FROM audit_log WHERE target = 'two'", [])
+            .is_err());
+
+        assert_eq!(
+            Vault::get_audit_log(&conn, Some(1)).unwrap()[0].target,
+            "three"
+        );
+        assert_eq!(
+            Vault::check_audit_chain(&conn, KEY).unwrap(),
+            AuditVerification {
+                checked: 3,
+                broken: Vec::new(),
+                head_valid: true,
+            }
+        );
+        assert!(!Vault::check_audit_chain(&conn, b"other key")
+            .unwrap()
+            .is_valid());
+
+        conn.execute_batch(
+            "DROP TRIGGER audit_log_no_update;
+             UPDATE audit_log SET target = 'other' WHERE target = 'two';",
+        )
+        .unwrap();
+        assert_eq!(Vault::check_audit_chain(&conn, KEY).unwrap().broken, [2]);
+    }
+
+    #[test]
+    fn test_audit_log_rebuilt_without_key() {
+        let conn = log_with(&["one", "two", "three"]);
+        conn.execute_batch("DROP TRIGGER audit_log_no_update")
+            .unwrap();
+
+        // Edit an entry and rebuild every hash after it, which needs the key
+        let entries = Vault::get_audit_log(&conn, None).unwrap();
+        let mut prev_hash = String::new();
+        for mut entry in entries {
+            if entry.target == "two" {
+                entry.target = "other".to_string();
+            }
+            let hash = entry_hash(b"guessed key", &prev_hash, &entry);
+            conn.execute(
+                "UPDATE audit_log SET target = ?1, prev_hash = ?2, hash = ?3 WHERE id = ?4",
+                rusqlite::params![&entry.target, &prev_hash, &hash, entry.id],
+            )
+            .unwrap();
+            prev_hash = hash;
+        }
+        conn.execute(
+            "UPDATE audit_head SET hash = ?1",
+            rusqlite::params![&prev_hash],
+        )
+        .unwrap();
+        let verification = Vault::check_audit_chain(&conn, KEY).unwrap();
+        assert_eq!(verification.broken, [1, 2, 3]);
+        assert!(!verification.head_valid);
+    }
+
+    #[test]
+    fn test_audit_log_truncated() {
+        let conn = log_with(&["one", "two", "three"]);
+        conn.execute_batch(
+            "DROP TRIGGER audit_log_no_delete;
+             DELETE FROM audit_log WHERE target = 'three';",
+        )
+        .unwrap();
+
+        let verification = Vault::check_audit_chain(&conn, KEY).unwrap();
+        assert!(verification.broken.is_empty());
+        assert!(!verification.head_valid);
+
+        // Dropping the head as well does not help
+        conn.execute("DELETE FROM audit_head", []).unwrap();
+        assert!(!Vault::check_audit_chain(&conn, KEY).unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_legacy_audit_log() {
+        let conn = log_with(&[]);
+        // Entries of older versions are chained with plain SHA-256 and have no head
+        let mut prev_hash = String::new();
+        for target in ["one", "two"] {
+            let entry = AuditEntry {
+                id: 0,
+                created_at: "2024-01-01 00:00:00".to_string(),
+                operation: "secret_show".to_string(),
+                target: target.to_string(),
+                os_user: "alice".to_string(),
+                key_fingerprint: None,
+            };
+            let hash = legacy_entry_hash(&prev_hash, &entry);
+            conn.execute(
+                "INSERT INTO audit_log
+                    (created_at, operation, target, os_user, prev_hash, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    &entry.created_at,
+                    &entry.operation,
+                    &entry.target,
+                    &entry.os_user,
+                    &prev_hash,
+                    &hash
+                ],
+            )
+            .unwrap();
+            prev_hash = hash;
+        }
+        assert!(!Vault::check_audit_chain(&conn, KEY).unwrap().head_valid);
+
+        // The next entry is keyed and seals the log
+        Vault::audit(&conn, KEY, "secret_show", "three").unwrap();
+        assert_eq!(
+            Vault::check_audit_chain(&conn, KEY).unwrap(),
+            AuditVerification {
+                checked: 3,
+                broken: Vec::new(),
+                head_valid: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use crate::crypto::{decrypt_stream, verify_key, Encryption, Verification};
+use crate::error::DmError;
+use crate::files::write_atomic;
+use crate::schema::{migrate, schema_version, SCHEMA_VERSION};
+use crate::settings::{Compression, SignaturePolicy, VaultSettings};
+use crate::vault::Vault;
+use crate::GPG_KEY_HASH_CONFIG;
+use rusqlite::{Connection, DatabaseName};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+
+/// First bytes of every SQLite database file.
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// A validated backup, unpacked into a temporary database.
+pub struct Backup {
+    snapshot: tempfile::NamedTempFile,
+    pub path: PathBuf,
+    pub encrypted: bool,
+    /// Schema version the backup was made with, it is upgraded on restore
+    pub schema_version: i64,
+    pub key_hash: String,
+    pub recipients: Vec<String>,
+    pub signatures: SignaturePolicy,
+    pub secrets: i64,
+    pub files: i64,
+}
+
+/// Entries added to a vault from a backup by [`Vault::merge_backup`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MergedEntries {
+    pub secrets: Vec<String>,
+    pub files: Vec<String>,
+    /// Secret names and file paths that were already in the vault
+    pub skipped: Vec<String>,
+}
+
+impl Vault {
+    /// Writes a consistent snapshot of the vault to `output`, also while other processes
+    /// use it. With `encrypt` the snapshot is encrypted and signed as a single OpenPGP
+    /// message, so names and paths of stored entries are hidden too.
+    pub fn backup(&self, output: impl AsRef<Path>, encrypt: bool) -> Result<(), DmError> {
+        let output = output.as_ref();
+        let snapshot = tempfile::NamedTempFile::new()?;
+        self.conn
+            .backup(DatabaseName::Main, snapshot.path(), None)?;
+
+        let mut snapshot = snapshot.reopen()?;
+        write_atomic(output, None, false, |file| {
+            if encrypt {
+                // Entries inside are compressed already if at all
+                let encryption = Encryption {
+                    recipients: self.recipients()?,
+                    settings: VaultSettings {
+                        armor: false,
+                        compression: Compression::None,
+                        ..VaultSettings::default()
+                    },
+                };
+                encryption.encrypt_stream(&snapshot, file)
+            } else {
+                io::copy(&mut snapshot, file)?;
+                Ok(())
+            }
+        })?;
+        Self::audit(
+            &self.conn,
+            &self.audit_key()?,
+            "backup",
+            &output.display().to_string(),
+        )
+    }
+
+    /// Unpacks and validates a backup made by [`Vault::backup`] where there is no vault
+    /// yet: encrypted backups must be signed by one of `signers`, the database must be
+    /// intact, its schema supported and its vault key present in the keyring.
+    pub fn open_backup(path: impl AsRef<Path>, signers: &[String]) -> Result<Backup, DmError> {
+        Self::unpack_backup(path.as_ref(), || {
+            if signers.is_empty() {
+                return Err(DmError::InvalidInput(
+                    "Allowed signers are needed to verify an encrypted backup".to_string(),
+                ));
+            }
+            Verification::new(signers, SignaturePolicy::Require, false)
+        })
+    }
+
+    /// Like [`Vault::open_backup`], but encrypted backups must be signed by a signer this
+    /// vault accepts. See [`Vault::check_backup`] before replacing the vault with it.
+    pub fn read_backup(&self, path: impl AsRef<Path>) -> Result<Backup, DmError> {
+        let settings = Self::verification_settings(&self.conn, self.signers.as_deref(), None)?;
+        Self::unpack_backup(path.as_ref(), || {
+            Verification::new(
+                &settings.signers,
+                SignaturePolicy::Require,
+                settings.from_vault,
+            )
+        })
+    }
+
+    /// Refuses to replace this vault with a backup of another vault key, unless that key
+    /// is a pinned signer. Without `trust_settings` it also refuses a backup that would
+    /// relax the signature policy or add recipients. Plain backups are not signed, so
+    /// this is all that keeps a crafted one from weakening the vault.
+    pub fn check_backup(&self, backup: &Backup, trust_settings: bool) -> Result<(), DmError> {
+        Self::check_replacement(&self.conn, self.signers.as_deref(), backup, trust_settings)
+    }
+
+    fn check_replacement(
+        conn: &Connection,
+        pinned_signers: Option<&[String]>,
+        backup: &Backup,
+        trust_settings: bool,
+    ) -> Result<(), DmError> {
+        let key_hash = Self::get_config(conn, GPG_KEY_HASH_CONFIG)?;
+        let pinned = pinned_signers.is_some_and(|signers| signers.contains(&backup.key_hash));
+        if key_hash.as_ref() != Some(&backup.key_hash) && !pinned {
+            return Err(DmError::BackupRejected(format!(
+                "its vault key {} is neither this vault's key nor an allowed signer in DARK_MATTER_SIGNERS",
+                backup.key_hash
+            )));
+        }
+        if trust_settings {
+            return Ok(());
+        }
+
+        if backup.signatures == SignaturePolicy::Warn
+            && Self::get_settings(conn)?.signatures == SignaturePolicy::Require
+        {
+            return Err(DmError::BackupRejected(
+                "it only warns about unverified signatures where this vault requires them"
+                    .to_string(),
+            ));
+        }
+        let recipients = Self::get_recipients(conn)?;
+        let added: Vec<_> = backup
+            .recipients
+            .iter()
+            .filter(|r| !recipients.contains(r))
+            .map(String::as_str)
+            .collect();
+        if !added.is_empty() {
+            return Err(DmError::BackupRejected(format!(
+                "it adds recipients {}",
+                added.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    fn unpack_backup(
+        path: &Path,
+        verification: impl FnOnce() -> Result<Verification, DmError>,
+    ) -> Result<Backup, DmError> {
+        let mut input = fs::File::open(path)?;
+        let mut header = Vec::new();
+        (&mut input)
+            .take(SQLITE_HEADER.len() as u64)
+            .read_to_end(&mut header)?;
+        input.rewind()?;
+
+        let mut snapshot = tempfile::NamedTempFile::new()?;
+        let encrypted = header != SQLITE_HEADER;
+        if encrypted {
+            let verification = verification()?.with_subject(format!("backup '{}'", path.display()));
+            decrypt_stream(
+                &input,
+                snapshot.as_file_mut(),
+                Compression::None,
+                &verification,
+            )?;
+        } else {
+            io::copy(&mut input, snapshot.as_file_mut())?;
+        }
+
+        let invalid = |reason: String| {
+            DmError::InvalidInput(format!(
+                "'{}' is not a valid vault backup: {}",
+                path.display(),
+                reason
+            ))
+        };
+        let mut conn = Connection::open(snapshot.path())?;
+        let integrity: String = conn
+            .query_row("PRAGMA integrity_check", [], |row| row.get(0))
+            .map_err(|e| invalid(e.to_string()))?;
+        if integrity != "ok" {
+            return Err(invalid(integrity));
+        }
+
+        let schema_version = schema_version(&conn)?;
+        if schema_version > SCHEMA_VERSION {
+            return Err(DmError::UnsupportedSchemaVersion(schema_version));
+        }
+        // The snapshot is a private copy, upgrading it shows the settings a restore ends up with
+        migrate(&mut conn)?;
+        let key_hash = Self::get_config(&conn, GPG_KEY_HASH_CONFIG)?
+            .ok_or_else(|| invalid("no vault key".to_string()))?;
+        verify_key(&key_hash)?;
+
+        let secrets = conn.query_row("SELECT COUNT(*) FROM secrets", [], |row| row.get(0))?;
+        let files = conn.query_row("SELECT COUNT(*) FROM flist", [], |row| row.get(0))?;
+
+        let recipients = Self::get_recipients(&conn)?;
+        let signatures = Self::get_settings(&conn)?.signatures;
+        drop(conn);
+
+        Ok(Backup {
+            snapshot,
+            path: path.to_path_buf(),
+            encrypted,
+            schema_version,
+            key_hash,
+            recipients,
+            signatures,
+            secrets,
+            files,
+        })
+    }
+
+    /// Replaces the vault at `path`, if any, with the backup and opens it.
+    /// Close other handles of that vault first.
+    pub fn restore_backup(backup: &Backup, path: impl AsRef<Path>) -> Result<Self, DmError> {
+        let path = path.as_ref();
+        let mut snapshot = backup.snapshot.reopen()?;
+        write_atomic(path, None, false, |file| {
+            io::copy(&mut snapshot, file)?;
+            Ok(())
+        })?;
+
+        let vault = Vault::open(path)?;
+        Self::audit(
+            &vault.conn,
+            &vault.audit_key()?,
+            "restore",
+            &backup.path.display().to_string(),
+        )?;
+        Ok(vault)
+    }
+
+    /// Adds secrets and files of the backup that are missing from this vault, with their
+    /// current value and re-encrypted to this vault's recipients. History is not merged.
+    /// Content is checked against this vault's allowed signers and signature policy,
+    /// whatever the backup's settings say. Either all are added or none.
+    pub fn merge_backup(&mut self, backup: &Backup) -> Result<MergedEntries, DmError> {
+        // The snapshot is a private copy, upgrading its schema leaves the backup untouched
+        let source = Vault::open(backup.snapshot.path())?;
+        // Signers and policy are this vault's, the backup's own settings are not trusted
+        let verification = self.verification()?;
+
+        let encryption = Self::encryption(&self.conn)?;
+        let key = self.content_hash_key()?;
+        let mut merged = MergedEntries::default();
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        for secret in source.list_secrets("")? {
+            let exists: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM secrets WHERE name = ?1)",
+                rusqlite::params![&secret.name],
+                |row| row.get(0),
+            )?;
+            if exists {
+                merged.skipped.push(secret.name);
+                continue;
+            }
+
+            let value = Self::decrypt_secret(&source.conn, &secret.name, None, &verification)?;
+            Self::insert_secret(
+                &tx,
+                &encryption,
+                &audit_key,
+                &secret.name,
+                &value,
+                &secret.tags.join(","),
+            )?;
+            merged.secrets.push(secret.name);
+        }
+
+        for realpath in source.list_files()? {
+            let exists: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM flist WHERE realpath = ?1)",
+                rusqlite::params![&realpath],
+                |row| row.get(0),
+            )?;
+            if exists {
+                merged.skipped.push(realpath);
+                continue;
+            }
+
+            Self::import_file(
+                &tx,
+                &source,
+                &realpath,
+                &encryption,
+                &verification,
+                &key,
+                &audit_key,
+            )?;
+            merged.files.push(realpath);
+        }
+        Self::audit(&tx, &audit_key, "merge", &backup.path.display().to_string())?;
+        tx.commit()?;
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SIGNATURES_CONFIG;
+
+    #[test]
+    fn test_check_replacement() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Vault::set_config(&conn, GPG_KEY_HASH_CONFIG, "VAULT").unwrap();
+        Vault::set_config(&conn, SIGNATURES_CONFIG, "require").unwrap();
+        for key_hash in ["VAULT", "ALICE"] {
+            conn.execute(
+                "INSERT INTO recipients (key_hash) VALUES (?1)",
+                rusqlite::params![key_hash],
+            )
+            .unwrap();
+        }
+        let backup = |key_hash: &str, recipients: &[&str], signatures| Backup {
+            snapshot: tempfile::NamedTempFile::new().unwrap(),
+            path: PathBuf::from("backup.db"),
+            encrypted: false,
+            schema_version: SCHEMA_VERSION,
+            key_hash: key_hash.to_string(),
+            recipients: recipients.iter().map(|r| r.to_string()).collect(),
+            signatures,
+            secrets: 0,
+            files: 0,
+        };
+        let check = |backup: &Backup, pinned: Option<&[String]>, trust| {
+            Vault::check_replacement(&conn, pinned, backup, trust)
+        };
+
+        let own = backup("VAULT", &["VAULT"], SignaturePolicy::Require);
+        assert!(check(&own, None, false).is_ok());
+
+        // A backup of another vault key is refused unless that key is pinned
+        let foreign = backup("FOREIGN", &["FOREIGN"], SignaturePolicy::Require);
+        assert!(matches!(
+            check(&foreign, None, true),
+            Err(DmError::BackupRejected(_))
+        ));
+        let pinned = ["FOREIGN".to_string()];
+        assert!(check(&foreign, Some(&pinned), true).is_ok());
+
+        // Relaxing the policy or adding recipients needs trust_settings
+        for backup in [
+            backup("VAULT", &["VAULT"], SignaturePolicy::Warn),
+            backup("VAULT", &["VAULT", "MALLORY"], SignaturePolicy::Require),
+        ] {
+            assert!(matches!(
+                check(&backup, None, false),
+                Err(DmError::BackupRejected(_))
+            ));
+            assert!(check(&backup, None, true).is_ok());
+        }
+    }
+}
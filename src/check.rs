@@ -0,0 +1,366 @@
+use crate::crypto::{decrypt_stream, Verification};
+use crate::error::DmError;
+use crate::settings::Compression;
+use crate::vault::Vault;
+use crate::CONTENT_HASH_KEY_CONFIG;
+use base64::Engine;
+use gpgme::{Context, Protocol};
+use serde::Serialize;
+use std::io::{self, Read};
+
+/// Only the start of a message is parsed, key packets come before the encrypted data.
+const HEADER_PREFIX_SIZE: u64 = 64 * 1024;
+
+/// Outcome of [`Vault::check`].
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    /// Problems found by SQLite `integrity_check`
+    pub integrity: Vec<String>,
+    pub entries: Vec<EntryCheck>,
+}
+
+impl CheckReport {
+    pub fn problems(&self) -> usize {
+        self.integrity.len() + self.entries.iter().filter(|e| e.error.is_some()).count()
+    }
+}
+
+/// Result of checking one stored blob.
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryCheck {
+    /// `secret`, `secret_version`, `file_version` or `content_hash_key`
+    pub kind: &'static str,
+    pub name: String,
+    pub version: Option<i64>,
+    /// Key ids the blob is encrypted to, `0000000000000000` for hidden recipients
+    pub key_ids: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl Vault {
+    /// Checks the database and every stored secret and file, including history.
+    /// With `decrypt` each blob is decrypted and its signature verified, otherwise
+    /// only OpenPGP packet headers are parsed, which needs no secret key.
+    pub fn check(&self, decrypt: bool) -> Result<CheckReport, DmError> {
+        let mut stmt = self.conn.prepare("PRAGMA integrity_check")?;
+        let integrity = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|message| message != "ok")
+            .collect();
+
+        let recipients = recipient_key_ids(&self.recipients()?);
+        let verification = if decrypt {
+            Some(self.verification()?)
+        } else {
+            None
+        };
+        let check = |kind, name: String, version, ciphertext: &mut dyn ReadSeek, compression| {
+            let mut entry = EntryCheck {
+                kind,
+                name,
+                version,
+                key_ids: Vec::new(),
+                error: None,
+            };
+            let result = check_blob(
+                ciphertext,
+                compression,
+                &recipients,
+                verification.as_ref(),
+                &mut entry.key_ids,
+            );
+            // The report names the entry, the generic prefix adds nothing
+            entry.error = result
+                .err()
+                .map(|e| e.to_string().trim_start_matches("Error: ").to_string());
+            entry
+        };
+
+        let mut entries = Vec::new();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, body, compression FROM secrets ORDER BY name")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<(String, Vec<u8>, Option<String>)>, _>>()?;
+        for (name, body, compression) in rows {
+            let compression = Compression::from_column(compression.as_deref())?;
+            entries.push(check(
+                "secret",
+                name,
+                None,
+                &mut io::Cursor::new(body),
+                compression,
+            ));
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT name, version, body, compression FROM secret_versions ORDER BY name, version",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<(String, i64, Vec<u8>, Option<String>)>, _>>()?;
+        for (name, version, body, compression) in rows {
+            let compression = Compression::from_column(compression.as_deref())?;
+            let mut body = io::Cursor::new(body);
+            entries.push(check(
+                "secret_version",
+                name,
+                Some(version),
+                &mut body,
+                compression,
+            ));
+        }
+
+        let mut stmt = self.conn.prepare(
+            "SELECT id, realpath, version, compression FROM file_versions
+             ORDER BY realpath, version",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<(i64, String, i64, Option<String>)>, _>>()?;
+        for (id, realpath, version, compression) in rows {
+            let compression = Compression::from_column(compression.as_deref())?;
+            let mut ciphertext = Self::read_chunks(&self.conn, id)?;
+            entries.push(check(
+                "file_version",
+                realpath,
+                Some(version),
+                &mut ciphertext,
+                compression,
+            ));
+        }
+
+        if let Some(key) = Self::get_config(&self.conn, CONTENT_HASH_KEY_CONFIG)? {
+            // Broken base64 is reported like any other malformed message
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(key)
+                .unwrap_or_default();
+            entries.push(check(
+                "content_hash_key",
+                CONTENT_HASH_KEY_CONFIG.to_string(),
+                None,
+                &mut io::Cursor::new(key),
+                Compression::None,
+            ));
+        }
+
+        Ok(CheckReport { integrity, entries })
+    }
+}
+
+trait ReadSeek: Read + io::Seek + Send {}
+
+impl<T: Read + io::Seek + Send> ReadSeek for T {}
+
+fn check_blob(
+    ciphertext: &mut dyn ReadSeek,
+    compression: Compression,
+    recipients: &[(String, Vec<String>)],
+    verification: Option<&Verification>,
+    key_ids: &mut Vec<String>,
+) -> Result<(), DmError> {
+    let mut prefix = Vec::new();
+    (&mut *ciphertext)
+        .take(HEADER_PREFIX_SIZE)
+        .read_to_end(&mut prefix)?;
+    *key_ids = message_key_ids(&prefix)?;
+
+    // Only keys found in the keyring can be matched to key ids
+    for (recipient, subkey_ids) in recipients {
+        let encrypted_to = key_ids
+            .iter()
+            .any(|id| subkey_ids.iter().any(|s| s.eq_ignore_ascii_case(id)));
+        if !encrypted_to {
+            return Err(DmError::InvalidInput(format!(
+                "not encrypted to recipient '{}'",
+                recipient
+            )));
+        }
+    }
+
+    if let Some(verification) = verification {
+        ciphertext.rewind()?;
+        decrypt_stream(ciphertext, io::sink(), compression, verification)?;
+    }
+    Ok(())
+}
+
+/// Key ids of the recipients' subkeys, for recipients found in the keyring.
+fn recipient_key_ids(recipients: &[String]) -> Vec<(String, Vec<String>)> {
+    let Ok(mut ctx) = Context::from_protocol(Protocol::OpenPgp) else {
+        return Vec::new();
+    };
+    recipients
+        .iter()
+        .filter_map(|recipient| {
+            let key = ctx.get_key(recipient.as_str()).ok()?;
+            let ids = key
+                .subkeys()
+                .filter_map(|subkey| subkey.id().ok().map(String::from))
+                .collect();
+            Some((recipient.clone(), ids))
+        })
+        .collect()
+}
+
+/// Parses the packets before the encrypted data of an OpenPGP message, armored or
+/// binary, and returns the key ids of its public-key encrypted session keys.
+fn message_key_ids(message: &[u8]) -> Result<Vec<String>, DmError> {
+    let binary;
+    let mut packets = if message.starts_with(b"-----BEGIN PGP MESSAGE-----") {
+        binary = dearmor(message)?;
+        &binary[..]
+    } else {
+        message
+    };
+
+    let invalid =
+        |reason: &str| DmError::InvalidInput(format!("invalid OpenPGP message: {}", reason));
+    let mut key_ids = Vec::new();
+    loop {
+        let (tag, body, rest) = next_packet(packets).ok_or_else(|| invalid("truncated packet"))?;
+        match tag {
+            // Public-key encrypted session key
+            1 => key_ids.push(pkesk_key_id(body).ok_or_else(|| invalid("bad session key packet"))?),
+            // Marker packet
+            10 => {}
+            // Encrypted data follows the session keys
+            9 | 18 | 20 => break,
+            tag => return Err(invalid(&format!("unexpected packet type {}", tag))),
+        }
+        packets = rest;
+    }
+
+    if key_ids.is_empty() {
+        return Err(invalid("no public-key encrypted session key"));
+    }
+    Ok(key_ids)
+}
+
+/// Splits the next packet off `data`, returning its tag and the available part of its body.
+/// Bodies of data packets may extend past `data`, which only holds the start of the message.
+fn next_packet(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&first, data) = data.split_first()?;
+    if first & 0x80 == 0 {
+        return None;
+    }
+
+    let (tag, len, data) = if first & 0x40 != 0 {
+        // New format
+        let tag = first & 0x3f;
+        let (&o1, data) = data.split_first()?;
+        match o1 {
+            0..=191 => (tag, o1 as usize, data),
+            192..=223 => {
+                let (&o2, data) = data.split_first()?;
+                (tag, ((o1 as usize - 192) << 8) + o2 as usize + 192, data)
+            }
+            255 => {
+                let (len, data) = data.split_at_checked(4)?;
+                (tag, u32::from_be_bytes(len.try_into().ok()?) as usize, data)
+            }
+            // Partial body length
+            _ => (tag, 1 << (o1 & 0x1f), data),
+        }
+    } else {
+        // Old format
+        let tag = (first >> 2) & 0x0f;
+        let (len, data) = match first & 0x03 {
+            0 => data.split_at_checked(1)?,
+            1 => data.split_at_checked(2)?,
+            2 => data.split_at_checked(4)?,
+            _ => (&[][..], data),
+        };
+        let len = if len.is_empty() {
+            data.len()
+        } else {
+            len.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+        };
+        (tag, len, data)
+    };
+
+    if matches!(tag, 9 | 18 | 20) {
+        let len = len.min(data.len());
+        return Some((tag, &data[..len], &data[len..]));
+    }
+    let (body, rest) = data.split_at_checked(len)?;
+    Some((tag, body, rest))
+}
+
+fn pkesk_key_id(body: &[u8]) -> Option<String> {
+    let id = match body.first()? {
+        3 => body.get(1..9)?,
+        // Version 6 names the key by fingerprint, whose last 8 bytes are not the key id,
+        // so the whole fingerprint is reported
+        6 => {
+            let len = *body.get(1)? as usize;
+            body.get(3..2 + len)?
+        }
+        _ => return None,
+    };
+    Some(id.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+/// Decodes the body of an ASCII armored message. A truncated last line is ignored.
+fn dearmor(message: &[u8]) -> Result<Vec<u8>, DmError> {
+    let text = String::from_utf8_lossy(message);
+    let mut lines = text.lines().skip(1);
+
+    // Armor headers end with an empty line
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut binary = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.starts_with('=') || line.starts_with("-----") {
+            break;
+        }
+        match base64::engine::general_purpose::STANDARD.decode(line) {
+            Ok(bytes) => binary.extend(bytes),
+            Err(_) => break,
+        }
+    }
+    Ok(binary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_key_ids() {
+        let mut message = Vec::new();
+        for key_id in [[0xAB; 8], [0x01; 8]] {
+            // New format PKESK v3 with RSA algorithm and a dummy session key
+            message.extend([0xC1, 12, 3]);
+            message.extend(key_id);
+            message.extend([1, 0, 0]);
+        }
+        // Old format SEIPD with indeterminate length
+        message.extend([0xA7, 1, 0xFF, 0xFF]);
+
+        let expected = ["ABABABABABABABAB", "0101010101010101"];
+        assert_eq!(message_key_ids(&message).unwrap(), expected);
+
+        let armored = format!(
+            "-----BEGIN PGP MESSAGE-----\n\n{}\n=abcd\n-----END PGP MESSAGE-----\n",
+            base64::engine::general_purpose::STANDARD.encode(&message)
+        );
+        assert_eq!(message_key_ids(armored.as_bytes()).unwrap(), expected);
+
+        assert!(message_key_ids(&message[..10]).is_err());
+        assert!(message_key_ids(&message[30..]).is_err());
+        assert!(message_key_ids(b"not a message").is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use crate::error::DmError;
+use crate::settings::{Compression, SignaturePolicy, VaultSettings};
+use gpgme::{Context, Data, Protocol, VerificationResult};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+/// Capabilities and details of a GPG key as reported by [`diagnose_key`].
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyDiagnostics {
+    pub id: Option<String>,
+    pub fingerprint: Option<String>,
+    pub can_encrypt: bool,
+    pub can_sign: bool,
+    pub can_certify: bool,
+    pub can_authenticate: bool,
+    pub subkeys: Vec<SubkeyInfo>,
+    pub user_ids: Vec<UserIdInfo>,
+    pub encryption_test: EncryptionTest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubkeyInfo {
+    pub id: Option<String>,
+    pub can_encrypt: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserIdInfo {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Outcome of encrypting a test message to the diagnosed key.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", content = "error", rename_all = "snake_case")]
+pub enum EncryptionTest {
+    Passed,
+    Failed(String),
+    Skipped,
+}
+
+/// Checks that a GPG key exists and can be used for encryption.
+pub fn verify_key(key_hash: &str) -> Result<(), DmError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+
+    // Get the key by hash
+    match ctx.get_key(key_hash) {
+        Ok(key) => {
+            // Check if the key can be used for encryption
+            if key.can_encrypt() {
+                Ok(())
+            } else {
+                Err(DmError::GpgKeyNotFound(format!(
+                    "{} (key cannot be used for encryption)",
+                    key_hash
+                )))
+            }
+        }
+        Err(_) => Err(DmError::GpgKeyNotFound(key_hash.to_string())),
+    }
+}
+
+/// Collects capabilities of a GPG key and tries to encrypt a test message to it.
+pub fn diagnose_key(key_hash: &str) -> Result<KeyDiagnostics, DmError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+
+    let key = ctx
+        .get_key(key_hash)
+        .map_err(|_| DmError::GpgKeyNotFound(key_hash.to_string()))?;
+
+    let subkeys = key
+        .subkeys()
+        .map(|subkey| SubkeyInfo {
+            id: subkey.id().ok().map(String::from),
+            can_encrypt: subkey.can_encrypt(),
+        })
+        .collect();
+
+    let user_ids = key
+        .user_ids()
+        .map(|uid| UserIdInfo {
+            name: uid.name().ok().map(String::from),
+            email: uid.email().ok().map(String::from),
+        })
+        .collect();
+
+    // Test encryption capability with a small message
+    let encryption_test = if key.can_encrypt() {
+        let test_data = b"Test encryption capability";
+        match encrypt_stream(
+            &test_data[..],
+            io::sink(),
+            &[key_hash.to_string()],
+            true,
+            false,
+        ) {
+            Ok(_) => EncryptionTest::Passed,
+            Err(e) => EncryptionTest::Failed(e.to_string()),
+        }
+    } else {
+        EncryptionTest::Skipped
+    };
+
+    Ok(KeyDiagnostics {
+        id: key.id().ok().map(String::from),
+        fingerprint: key.fingerprint().ok().map(String::from),
+        can_encrypt: key.can_encrypt(),
+        can_sign: key.can_sign(),
+        can_certify: key.can_certify(),
+        can_authenticate: key.can_authenticate(),
+        subkeys,
+        user_ids,
+        encryption_test,
+    })
+}
+
+/// Recipients and settings new content is encrypted with. Content is signed with
+/// the first recipient whose secret key is available, which is usually the vault key.
+pub(crate) struct Encryption {
+    pub(crate) recipients: Vec<String>,
+    pub(crate) settings: VaultSettings,
+}
+
+impl Encryption {
+    /// Compresses content as configured and encrypts it. Store the compression
+    /// from `settings` alongside, it is needed to decrypt the result.
+    pub(crate) fn encrypt(&self, content: &[u8]) -> Result<Vec<u8>, DmError> {
+        let mut output = Vec::new();
+        self.encrypt_stream(content, &mut output)?;
+        Ok(output)
+    }
+
+    /// Encrypts content that has no compression recorded with it.
+    pub(crate) fn encrypt_uncompressed(&self, content: &[u8]) -> Result<Vec<u8>, DmError> {
+        let mut output = Vec::new();
+        encrypt_stream(
+            content,
+            &mut output,
+            &self.recipients,
+            self.settings.armor,
+            true,
+        )?;
+        Ok(output)
+    }
+
+    pub(crate) fn encrypt_stream(
+        &self,
+        input: impl Read + Send,
+        output: impl Write + Send,
+    ) -> Result<(), DmError> {
+        match self.settings.compression {
+            Compression::None => {
+                encrypt_stream(input, output, &self.recipients, self.settings.armor, true)
+            }
+            Compression::Zstd => {
+                let input = zstd::stream::read::Encoder::new(input, 0)?;
+                encrypt_stream(input, output, &self.recipients, self.settings.armor, true)
+            }
+        }
+    }
+
+    /// Decrypts `input`, stored with `compression`, and encrypts it again. Plaintext
+    /// is passed between the two through a pipe, so it is neither buffered nor written to disk.
+    /// A copy of it goes to `plaintext`, e.g. a [`ContentHasher`].
+    pub(crate) fn reencrypt_stream(
+        &self,
+        input: impl Read + Send,
+        output: impl Write + Send,
+        compression: Compression,
+        verification: &Verification,
+        plaintext: impl Write + Send,
+    ) -> Result<(), DmError> {
+        let (reader, writer) = io::pipe()?;
+        std::thread::scope(|scope| {
+            let decrypt =
+                scope.spawn(move || decrypt_stream(input, writer, compression, verification));
+            let reader = TeeReader {
+                reader,
+                writer: plaintext,
+            };
+            let encrypted = self.encrypt_stream(reader, output);
+            let decrypted = decrypt.join().expect("decryption thread panicked");
+            // Failed encryption closes the pipe and so fails decryption as well,
+            // while failed decryption only ends the input of encryption early
+            encrypted.and(decrypted)
+        })
+    }
+}
+
+/// Encrypts everything read from `input` into `output` without buffering it in memory.
+/// With `sign` the content is signed by the first recipient with an available secret key.
+fn encrypt_stream(
+    input: impl Read + Send,
+    output: impl Write + Send,
+    recipients: &[String],
+    armor: bool,
+    sign: bool,
+) -> Result<(), DmError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+
+    // Armor is more portable, binary ciphertext is about a third smaller
+    ctx.set_armor(armor);
+
+    // Get keys of all recipients
+    let mut keys = Vec::with_capacity(recipients.len());
+    for key_hash in recipients {
+        let key = ctx.get_key(key_hash.as_str())?;
+
+        // Check if key can encrypt
+        if !key.can_encrypt() {
+            return Err(DmError::GpgError(gpgme::Error::from_code(110))); // Generic unusable key error
+        }
+        keys.push(key);
+    }
+
+    // Set trust mode (trust all keys)
+    ctx.set_offline(true);
+
+    let input = Data::from_reader(input).map_err(|e| e.error())?;
+    let output = Data::from_writer(output).map_err(|e| e.error())?;
+    if sign {
+        let signer = recipients
+            .iter()
+            .find_map(|key_hash| ctx.get_secret_key(key_hash.as_str()).ok())
+            .ok_or(DmError::NoSigningKey)?;
+        ctx.add_signer(&signer)?;
+        ctx.sign_and_encrypt(&keys, input, output)?;
+    } else {
+        ctx.encrypt(&keys, input, output)?;
+    }
+    Ok(())
+}
+
+/// Receives messages about content used despite failed signature verification.
+pub(crate) type WarningHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Keys whose signatures are accepted on decrypted content, and what happens to
+/// content that is unsigned or signed by anyone else.
+#[derive(Clone)]
+pub(crate) struct Verification {
+    /// Fingerprints of allowed signers, including their subkeys
+    pub(crate) fingerprints: Vec<String>,
+    pub(crate) policy: SignaturePolicy,
+    pub(crate) warn: Option<WarningHandler>,
+    /// What is decrypted, for messages
+    pub(crate) subject: String,
+}
+
+impl Verification {
+    /// Resolves `signers` in the keyring. Keys missing from it are skipped,
+    /// their signatures could not be checked anyway. With `own_keys` only keys whose
+    /// secret key is in the keyring count, for signers read from the vault itself.
+    pub(crate) fn new(
+        signers: &[String],
+        policy: SignaturePolicy,
+        own_keys: bool,
+    ) -> Result<Self, DmError> {
+        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+        let mut fingerprints = Vec::new();
+        for key_hash in signers {
+            let key = if own_keys {
+                ctx.get_secret_key(key_hash.as_str())
+            } else {
+                ctx.get_key(key_hash.as_str())
+            };
+            if let Ok(key) = key {
+                fingerprints.extend(
+                    key.subkeys()
+                        .filter_map(|subkey| subkey.fingerprint().ok().map(String::from)),
+                );
+            }
+        }
+        Ok(Verification {
+            fingerprints,
+            policy,
+            warn: None,
+            subject: "content".to_string(),
+        })
+    }
+
+    pub(crate) fn with_subject(self, subject: String) -> Self {
+        Verification { subject, ..self }
+    }
+
+    fn check(&self, result: &VerificationResult) -> Result<(), DmError> {
+        let signatures: Vec<_> = result
+            .signatures()
+            .map(|signature| {
+                let fingerprint = signature.fingerprint().ok().map(String::from);
+                (signature.status().is_ok(), fingerprint)
+            })
+            .collect();
+        self.check_signatures(&signatures)
+    }
+
+    /// Applies the policy to signatures given as validity and signer fingerprint.
+    pub(crate) fn check_signatures(
+        &self,
+        signatures: &[(bool, Option<String>)],
+    ) -> Result<(), DmError> {
+        let verified = signatures.iter().any(|(valid, fingerprint)| {
+            *valid
+                && fingerprint.as_ref().is_some_and(|fpr| {
+                    self.fingerprints
+                        .iter()
+                        .any(|allowed| allowed.eq_ignore_ascii_case(fpr))
+                })
+        });
+        if verified {
+            return Ok(());
+        }
+
+        let reason = if signatures.is_empty() {
+            format!("{} is not signed", self.subject)
+        } else {
+            format!("{} is not signed by an allowed signer", self.subject)
+        };
+        match self.policy {
+            SignaturePolicy::Require => Err(DmError::SignatureNotVerified(reason)),
+            SignaturePolicy::Warn => {
+                if let Some(warn) = &self.warn {
+                    warn(&reason);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Keyed hash of plaintext, so changes can be detected without decrypting and
+/// without the hash revealing guessable content. Content is fed through [`Write`].
+pub(crate) struct ContentHasher {
+    mac: Hmac<Sha256>,
+    size: u64,
+}
+
+impl ContentHasher {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        ContentHasher {
+            mac: Hmac::new_from_slice(key).expect("HMAC accepts keys of any size"),
+            size: 0,
+        }
+    }
+
+    /// Number of bytes hashed so far.
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.mac.update(buf);
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader that copies everything it reads into `writer`.
+pub(crate) struct TeeReader<R, W> {
+    pub(crate) reader: R,
+    pub(crate) writer: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Fingerprint of the first of `recipients` whose secret key is in the local keyring,
+/// which is the key content is signed with.
+pub(crate) fn own_key_fingerprint(recipients: &[String]) -> Option<String> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp).ok()?;
+    recipients.iter().find_map(|key_hash| {
+        let key = ctx.get_secret_key(key_hash.as_str()).ok()?;
+        key.fingerprint().ok().map(String::from)
+    })
+}
+
+pub(crate) fn random_bytes(len: usize) -> Result<Vec<u8>, DmError> {
+    let mut bytes = vec![0; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| DmError::IoError(io::Error::other(e.to_string())))?;
+    Ok(bytes)
+}
+
+pub(crate) fn decrypt_content(
+    encrypted_content: &[u8],
+    compression: Compression,
+    verification: &Verification,
+) -> Result<Vec<u8>, DmError> {
+    let mut output = Vec::new();
+    decrypt_stream(encrypted_content, &mut output, compression, verification)?;
+    Ok(output)
+}
+
+/// Decrypts everything read from `input` into `output` without buffering it in
+/// memory, undoing the `compression` the content was stored with.
+///
+/// The signature can only be checked at the end, so on errors `output` may
+/// already hold unverified content and has to be discarded.
+pub(crate) fn decrypt_stream(
+    input: impl Read + Send,
+    output: impl Write + Send,
+    compression: Compression,
+    verification: &Verification,
+) -> Result<(), DmError> {
+    match compression {
+        Compression::None => decrypt_raw(input, output, verification),
+        Compression::Zstd => {
+            let mut decoder = zstd::stream::write::Decoder::new(output)?;
+            decrypt_raw(input, &mut decoder, verification)?;
+            decoder.flush()?;
+            Ok(())
+        }
+    }
+}
+
+/// Decrypts a message from outside the vault, such as a pass entry, without
+/// checking its signature. Such messages are usually not signed at all.
+pub(crate) fn decrypt_unverified(input: impl Read + Send) -> Result<Vec<u8>, DmError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+    let input = Data::from_reader(input).map_err(|e| e.error())?;
+    let mut output = Vec::new();
+    ctx.decrypt(input, &mut output)?;
+    Ok(output)
+}
+
+fn decrypt_raw(
+    input: impl Read + Send,
+    output: impl Write + Send,
+    verification: &Verification,
+) -> Result<(), DmError> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+
+    let input = Data::from_reader(input).map_err(|e| e.error())?;
+    let output = Data::from_writer(output).map_err(|e| e.error())?;
+    let (_, result) = ctx.decrypt_and_verify(input, output)?;
+    verification.check(&result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_signatures() {
+        let verification = Verification {
+            fingerprints: vec!["AAAA".to_string()],
+            policy: SignaturePolicy::Require,
+            warn: None,
+            subject: "secret 'api'".to_string(),
+        };
+        let signed_by = |fpr: &str, valid| vec![(valid, Some(fpr.to_string()))];
+
+        assert!(verification
+            .check_signatures(&signed_by("aaaa", true))
+            .is_ok());
+        for signatures in [
+            Vec::new(),
+            signed_by("AAAA", false),
+            // A key someone added to the vault recipients is not an allowed signer
+            signed_by("INJECTED", true),
+        ] {
+            assert!(matches!(
+                verification.check_signatures(&signatures),
+                Err(DmError::SignatureNotVerified(_))
+            ));
+        }
+
+        let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = warnings.clone();
+        let verification = Verification {
+            policy: SignaturePolicy::Warn,
+            warn: Some(Arc::new(move |message: &str| {
+                sink.lock().unwrap().push(message.to_string())
+            })),
+            ..verification
+        };
+        assert!(verification.check_signatures(&[]).is_ok());
+        assert_eq!(*warnings.lock().unwrap(), ["secret 'api' is not signed"]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::crypto::ContentHasher;
+use crate::error::DmError;
+use crate::files::absolute_path;
+use crate::vault::Vault;
+use serde::Serialize;
+use similar::TextDiff;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Difference between the vault copy of a file and the file on disk.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileDiff {
+    /// Unified diff of text content, empty when both copies are equal
+    Text { unified: String },
+    /// Summaries of binary content that cannot be diffed line by line
+    Binary {
+        vault: ContentSummary,
+        disk: ContentSummary,
+    },
+}
+
+impl FileDiff {
+    /// Whether the two copies differ.
+    pub fn changed(&self) -> bool {
+        match self {
+            FileDiff::Text { unified } => !unified.is_empty(),
+            FileDiff::Binary { vault, disk } => vault != disk,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ContentSummary {
+    pub size: usize,
+    /// Hex encoded HMAC-SHA256 of the content with the vault's content hash key,
+    /// so it cannot be matched against guessed content without the GPG key
+    pub content_hash: String,
+}
+
+impl Vault {
+    /// Compares the stored file, or the given version of it, with the file on disk.
+    pub fn diff_file(
+        &self,
+        filename: impl AsRef<Path>,
+        version: Option<i64>,
+    ) -> Result<FileDiff, DmError> {
+        let filename = filename.as_ref();
+        // Only decrypt, which may ask for a passphrase, once the diff can be made
+        if !filename.exists() {
+            return Err(DmError::FileNotFound(filename.display().to_string()));
+        }
+        let current = fs::read(filename)?;
+        let realpath = absolute_path(filename)?;
+        let mut stored = Vec::new();
+        self.decrypt_file_to(&realpath, version, &mut stored)?;
+        // The diff shows stored content but nothing is written out
+        Self::audit(&self.conn, &self.audit_key()?, "file_diff", &realpath)?;
+
+        let label = match version {
+            Some(version) => format!("vault:{} (v{})", realpath, version),
+            None => format!("vault:{}", realpath),
+        };
+        let key = self.content_hash_key()?;
+        Ok(diff(&stored, &current, &label, &realpath, &key))
+    }
+}
+
+/// Diffs `old` against `new`. Content that is not UTF-8 or holds NUL bytes counts as binary,
+/// it is summarized with hashes keyed with `key`.
+fn diff(old: &[u8], new: &[u8], old_label: &str, new_label: &str, key: &[u8]) -> FileDiff {
+    match (text(old), text(new)) {
+        (Some(old), Some(new)) => {
+            let unified = if old == new {
+                String::new()
+            } else {
+                TextDiff::from_lines(old, new)
+                    .unified_diff()
+                    .header(old_label, new_label)
+                    .to_string()
+            };
+            FileDiff::Text { unified }
+        }
+        _ => FileDiff::Binary {
+            vault: summary(old, key),
+            disk: summary(new, key),
+        },
+    }
+}
+
+fn text(content: &[u8]) -> Option<&str> {
+    if content.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(content).ok()
+}
+
+fn summary(content: &[u8], key: &[u8]) -> ContentSummary {
+    let mut hasher = ContentHasher::new(key);
+    hasher
+        .write_all(content)
+        .expect("hashing into memory cannot fail");
+    ContentSummary {
+        size: content.len(),
+        content_hash: hasher.finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let text_diff = diff(b"a\nb\n", b"a\nc\n", "old", "new", b"key");
+        assert_eq!(
+            text_diff,
+            FileDiff::Text {
+                unified: "--- old\n+++ new\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n".to_string()
+            }
+        );
+        assert!(!diff(b"a\n", b"a\n", "old", "new", b"key").changed());
+
+        let binary_diff = diff(b"\x00\x01", b"\x00\x01", "old", "new", b"key");
+        assert!(matches!(binary_diff, FileDiff::Binary { .. }));
+        assert!(!binary_diff.changed());
+
+        // Hashes depend on the vault key, not on the content alone
+        let summaries = [b"key".as_slice(), b"other"].map(|key| summary(b"\x00\x01", key));
+        assert_ne!(summaries[0].content_hash, summaries[1].content_hash);
+        assert_eq!(summaries[0].size, 2);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::error::DmError;
+use crate::secrets::SecretInfo;
+use std::collections::BTreeMap;
+
+/// Output formats of [`format_env`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvFormat {
+    /// `NAME='value'` lines for dotenv loaders
+    Dotenv,
+    /// `export NAME='value'` lines for POSIX shells
+    Shell,
+    /// `NAME=value` lines for `docker run --env-file`, which has no quoting
+    DockerEnv,
+}
+
+/// Maps a secret name to an environment variable name: `db-password` becomes `DB_PASSWORD`.
+pub fn env_var_name(name: &str) -> String {
+    let mut var: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if var.is_empty() || var.starts_with(|c: char| c.is_ascii_digit()) {
+        var.insert(0, '_');
+    }
+    var
+}
+
+/// Maps decrypted secrets to environment variables, sorted by variable name.
+/// Fails if a value contains a NUL byte or two secrets map to the same variable.
+pub fn env_vars(secrets: Vec<(SecretInfo, Vec<u8>)>) -> Result<BTreeMap<String, Vec<u8>>, DmError> {
+    let mut vars = BTreeMap::new();
+    for (secret, value) in secrets {
+        let var = env_var_name(&secret.name);
+        if value.contains(&0) {
+            return Err(DmError::InvalidInput(format!(
+                "Secret '{}' contains a NUL byte and cannot be used as environment variable",
+                secret.name
+            )));
+        }
+        if vars.insert(var.clone(), value).is_some() {
+            return Err(DmError::InvalidInput(format!(
+                "Several secrets map to environment variable {}",
+                var
+            )));
+        }
+    }
+    Ok(vars)
+}
+
+/// Formats environment variables one per line, quoting values as `format` requires.
+pub fn format_env(vars: &BTreeMap<String, Vec<u8>>, format: EnvFormat) -> Result<String, DmError> {
+    let mut output = String::new();
+    for (var, value) in vars {
+        let value = std::str::from_utf8(value)
+            .map_err(|_| DmError::InvalidInput(format!("Value of {} is not valid UTF-8", var)))?;
+        let line = match format {
+            EnvFormat::Dotenv => format!("{}={}", var, dotenv_quote(value)),
+            EnvFormat::Shell => format!("export {}={}", var, shell_quote(value)),
+            EnvFormat::DockerEnv => {
+                if value.contains(['\n', '\r']) {
+                    return Err(DmError::InvalidInput(format!(
+                        "Value of {} spans several lines, which env files cannot hold",
+                        var
+                    )));
+                }
+                format!("{}={}", var, value)
+            }
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+fn shell_quote(value: &str) -> String {
+    // Inside single quotes only the quote itself needs care: end, escape, reopen
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn dotenv_quote(value: &str) -> String {
+    // Single quoted values are taken literally by dotenv loaders
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str(r"\\"),
+            '"' => quoted.push_str("\\\""),
+            '$' => quoted.push_str(r"\$"),
+            '\n' => quoted.push_str(r"\n"),
+            '\r' => quoted.push_str(r"\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_var_name() {
+        assert_eq!(env_var_name("db-password"), "DB_PASSWORD");
+        assert_eq!(env_var_name("api.key"), "API_KEY");
+        assert_eq!(env_var_name("2fa_seed"), "_2FA_SEED");
+    }
+
+    #[test]
+    fn test_format_env() {
+        let vars = BTreeMap::from([
+            ("A".to_string(), b"it's $HOME".to_vec()),
+            ("B".to_string(), b"plain".to_vec()),
+        ]);
+
+        assert_eq!(
+            format_env(&vars, EnvFormat::Dotenv).unwrap(),
+            "A=\"it's \\$HOME\"\nB='plain'\n"
+        );
+        assert_eq!(
+            format_env(&vars, EnvFormat::Shell).unwrap(),
+            "export A='it'\\''s $HOME'\nexport B='plain'\n"
+        );
+        assert_eq!(
+            format_env(&vars, EnvFormat::DockerEnv).unwrap(),
+            "A=it's $HOME\nB=plain\n"
+        );
+
+        let multiline = BTreeMap::from([("C".to_string(), b"a\nb".to_vec())]);
+        assert!(format_env(&multiline, EnvFormat::DockerEnv).is_err());
+    }
+}
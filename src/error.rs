@@ -0,0 +1,169 @@
+use std::io;
+
+/// Errors returned by vault operations.
+#[derive(Debug)]
+pub enum DmError {
+    DatabaseNotFound,
+    DatabaseAlreadyExists,
+    UnsupportedSchemaVersion(i64),
+    FileNotFound(String),
+    GpgKeyNotFound(String),
+    FileAlreadyExists(String),
+    FileNotInStorage(String),
+    FileVersionNotFound(String, i64),
+    SecretAlreadyExists(String),
+    SecretNotInStorage(String),
+    SecretVersionNotFound(String, i64),
+    UnknownSecrets(Vec<String>),
+    CannotChangeOwner(String, io::Error),
+    RecipientAlreadyExists(String),
+    RecipientNotFound(String),
+    RecipientIsVaultKey(String),
+    NoSigningKey,
+    SignatureNotVerified(String),
+    AuditLogBroken(Vec<i64>),
+    BackupRejected(String),
+    CheckFailed(usize),
+    InvalidInput(String),
+    DatabaseError(rusqlite::Error),
+    GpgError(gpgme::Error),
+    IoError(io::Error),
+}
+
+impl DmError {
+    /// Stable identifier of the error kind, used in machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DmError::DatabaseNotFound => "database_not_found",
+            DmError::DatabaseAlreadyExists => "database_already_exists",
+            DmError::UnsupportedSchemaVersion(..) => "unsupported_schema_version",
+            DmError::FileNotFound(..) => "file_not_found",
+            DmError::GpgKeyNotFound(..) => "gpg_key_not_found",
+            DmError::FileAlreadyExists(..) => "file_already_exists",
+            DmError::FileNotInStorage(..) => "file_not_in_storage",
+            DmError::FileVersionNotFound(..) => "file_version_not_found",
+            DmError::SecretAlreadyExists(..) => "secret_already_exists",
+            DmError::SecretNotInStorage(..) => "secret_not_in_storage",
+            DmError::SecretVersionNotFound(..) => "secret_version_not_found",
+            DmError::UnknownSecrets(..) => "unknown_secrets",
+            DmError::CannotChangeOwner(..) => "cannot_change_owner",
+            DmError::RecipientAlreadyExists(..) => "recipient_already_exists",
+            DmError::RecipientNotFound(..) => "recipient_not_found",
+            DmError::RecipientIsVaultKey(..) => "recipient_is_vault_key",
+            DmError::NoSigningKey => "no_signing_key",
+            DmError::SignatureNotVerified(..) => "signature_not_verified",
+            DmError::AuditLogBroken(..) => "audit_log_broken",
+            DmError::BackupRejected(..) => "backup_rejected",
+            DmError::CheckFailed(..) => "check_failed",
+            DmError::InvalidInput(..) => "invalid_input",
+            DmError::DatabaseError(..) => "database",
+            DmError::GpgError(..) => "gpg",
+            DmError::IoError(..) => "io",
+        }
+    }
+}
+
+impl std::fmt::Display for DmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DmError::DatabaseNotFound => {
+                write!(f, "Error: database dm-vault.db not found in current or parent directories. Please run 'dm init <gpg_key_hash>' to create a new vault or pass --vault <path>.")
+            }
+            DmError::DatabaseAlreadyExists => write!(f, "Error: database dm-vault.db already exists. Please remove it or use a different directory."),
+            DmError::UnsupportedSchemaVersion(version) => write!(
+                f,
+                "Error: vault schema version {} is newer than supported version {}. Please upgrade dark-matter.",
+                version, crate::SCHEMA_VERSION
+            ),
+            DmError::FileNotFound(path) => write!(f, "Error: File '{}' not found", path),
+            DmError::GpgKeyNotFound(hash) => {
+                write!(f, "Error: GPG key '{}' not found", hash)
+            }
+            DmError::SecretAlreadyExists(name) => {
+                write!(f, "Error: Secret '{}' already exists in vault", name)
+            }
+            DmError::SecretNotInStorage(name) => {
+                write!(f, "Error: Secret '{}' not found in vault", name)
+            }
+            DmError::SecretVersionNotFound(name, version) => {
+                write!(f, "Error: Version {} of secret '{}' not found in vault", version, name)
+            }
+            DmError::UnknownSecrets(names) => {
+                write!(f, "Error: Secrets not found in vault: {}", names.join(", "))
+            }
+            DmError::FileAlreadyExists(path) => write!(
+                f,
+                "Error: File '{}' already exists in vault. Use 'dm update <filename>' to update it.",
+                path
+            ),
+            DmError::FileNotInStorage(path) => {
+                write!(f, "Error: File '{}' not found in vault", path)
+            }
+            DmError::FileVersionNotFound(path, version) => {
+                write!(f, "Error: Version {} of file '{}' not found in vault", version, path)
+            }
+            DmError::CannotChangeOwner(path, e) => write!(
+                f,
+                "Error: Cannot restore owner of '{}' ({}). Run as root or pass --no-owner.",
+                path, e
+            ),
+            DmError::RecipientAlreadyExists(hash) => {
+                write!(f, "Error: Key '{}' is already a recipient of vault", hash)
+            }
+            DmError::RecipientNotFound(hash) => {
+                write!(f, "Error: Key '{}' is not a recipient of vault", hash)
+            }
+            DmError::RecipientIsVaultKey(hash) => write!(
+                f,
+                "Error: Key '{}' is the vault key and cannot be removed from recipients",
+                hash
+            ),
+            DmError::NoSigningKey => write!(
+                f,
+                "Error: No secret key of a vault recipient available to sign content"
+            ),
+            DmError::SignatureNotVerified(reason) => {
+                write!(f, "Error: Signature verification failed: {}", reason)
+            }
+            DmError::BackupRejected(reason) => {
+                write!(f, "Error: Backup cannot replace this vault, {}", reason)
+            }
+            DmError::AuditLogBroken(ids) if ids.is_empty() => write!(
+                f,
+                "Error: Audit log was modified, entries were removed from its end"
+            ),
+            DmError::AuditLogBroken(ids) => write!(
+                f,
+                "Error: Audit log was modified, {} entries do not match the hash chain",
+                ids.len()
+            ),
+            DmError::CheckFailed(problems) => {
+                write!(f, "Error: Vault check found {} problems", problems)
+            }
+            DmError::InvalidInput(message) => write!(f, "Error: {}", message),
+            DmError::DatabaseError(e) => write!(f, "Database error: {}", e),
+            DmError::GpgError(e) => write!(f, "GPG error: {}", e),
+            DmError::IoError(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DmError {}
+
+impl From<rusqlite::Error> for DmError {
+    fn from(error: rusqlite::Error) -> Self {
+        DmError::DatabaseError(error)
+    }
+}
+
+impl From<gpgme::Error> for DmError {
+    fn from(error: gpgme::Error) -> Self {
+        DmError::GpgError(error)
+    }
+}
+
+impl From<io::Error> for DmError {
+    fn from(error: io::Error) -> Self {
+        DmError::IoError(error)
+    }
+}
@@ -0,0 +1,983 @@
+use crate::crypto::{decrypt_stream, ContentHasher, Encryption, TeeReader, Verification};
+use crate::error::DmError;
+use crate::settings::Compression;
+use crate::vault::Vault;
+use ignore::WalkBuilder;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Name of the file listing gitignore-style patterns to skip when adding a directory.
+pub const IGNORE_FILE_NAME: &str = ".dmignore";
+
+/// Size of the pieces encrypted file content is stored in, see `file_chunks`.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Outcome of [`Vault::add_dir`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct AddedFiles {
+    pub added: Vec<String>,
+    /// Files that were already in the vault
+    pub skipped: Vec<String>,
+}
+
+/// State of a file on disk compared to the vault, see [`Vault::file_status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileState {
+    Unchanged,
+    Modified,
+    /// Stored in the vault but missing on disk
+    Missing,
+    /// On disk but not stored in the vault
+    New,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileStatus {
+    pub path: String,
+    pub state: FileState,
+}
+
+/// Unix metadata of a stored file, restored on export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FileMetadata {
+    /// Permission bits, e.g. `0o600`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: i64,
+}
+
+impl FileMetadata {
+    /// Reads metadata of a file on disk.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, DmError> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileMetadata {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mtime: metadata.mtime(),
+        })
+    }
+
+    /// Sets mtime and mode of an open file, and its owner when `owner` is set, which
+    /// usually needs root. `path` names the file in errors.
+    pub fn apply(
+        &self,
+        file: &fs::File,
+        path: impl AsRef<Path>,
+        owner: bool,
+    ) -> Result<(), DmError> {
+        let path = path.as_ref();
+
+        // Everything goes through the handle, the restored mode may not allow reopening
+        let mtime = if self.mtime >= 0 {
+            UNIX_EPOCH + Duration::from_secs(self.mtime as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(self.mtime.unsigned_abs())
+        };
+        file.set_modified(mtime)?;
+
+        // Changing the owner clears setuid bits, so the mode is set afterwards
+        if owner {
+            std::os::unix::fs::fchown(file, Some(self.uid), Some(self.gid))
+                .map_err(|e| DmError::CannotChangeOwner(path.display().to_string(), e))?;
+        }
+        file.set_permissions(fs::Permissions::from_mode(self.mode))?;
+        Ok(())
+    }
+}
+
+/// A stored revision of a file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileVersion {
+    pub version: i64,
+    /// Plaintext size in bytes, unknown for files vaulted before versioning.
+    pub size: Option<i64>,
+    pub created_at: String,
+    pub current: bool,
+}
+
+impl Vault {
+    /// Encrypts and stores a new file, returning the absolute path it is stored under.
+    pub fn add_file(&mut self, filename: impl AsRef<Path>) -> Result<String, DmError> {
+        let filename = filename.as_ref();
+        let realpath = absolute_path(filename)?;
+
+        // Check if file exists
+        if !filename.exists() {
+            return Err(DmError::FileNotFound(filename.display().to_string()));
+        }
+
+        // Check if file already added
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM flist WHERE realpath = ?1",
+            rusqlite::params![&realpath],
+            |row| row.get(0),
+        )?;
+
+        if count > 0 {
+            return Err(DmError::FileAlreadyExists(realpath));
+        }
+
+        // Read and encrypt content to all recipients
+        let encryption = Self::encryption(&self.conn)?;
+        let file = encrypt_file(filename, &encryption, &self.content_hash_key()?)?;
+
+        // Save to vault together with the first version
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::insert_file(&tx, &realpath, &file)?;
+        Self::audit(&tx, &audit_key, "file_add", &realpath)?;
+        tx.commit()?;
+        Ok(realpath)
+    }
+
+    /// Walks `dir` and stores every regular file not matched by a [`IGNORE_FILE_NAME`] file.
+    /// Files already in the vault are skipped. Either all files are added or none.
+    pub fn add_dir(&mut self, dir: impl AsRef<Path>) -> Result<AddedFiles, DmError> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(DmError::FileNotFound(dir.display().to_string()));
+        }
+
+        let paths = self.walk_dir(dir)?;
+
+        let encryption = Self::encryption(&self.conn)?;
+        let key = self.content_hash_key()?;
+        let mut added_files = AddedFiles::default();
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        for (path, realpath) in paths {
+            let count: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM flist WHERE realpath = ?1",
+                rusqlite::params![&realpath],
+                |row| row.get(0),
+            )?;
+            if count > 0 {
+                added_files.skipped.push(realpath);
+                continue;
+            }
+
+            let file = encrypt_file(&path, &encryption, &key)?;
+            Self::insert_file(&tx, &realpath, &file)?;
+            Self::audit(&tx, &audit_key, "file_add", &realpath)?;
+            added_files.added.push(realpath);
+        }
+        tx.commit()?;
+        Ok(added_files)
+    }
+
+    /// Lists absolute paths of all files in the vault.
+    pub fn list_files(&self) -> Result<Vec<String>, DmError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT realpath FROM flist ORDER BY realpath")?;
+        let file_iter = stmt.query_map([], |row| {
+            let path: String = row.get(0)?;
+            Ok(path)
+        })?;
+
+        let mut files = Vec::new();
+        for file in file_iter {
+            files.push(file?);
+        }
+        Ok(files)
+    }
+
+    /// Lists absolute paths of files stored under directory `prefix`, or `prefix` itself.
+    pub fn list_files_under(&self, prefix: impl AsRef<Path>) -> Result<Vec<String>, DmError> {
+        let prefixes = prefix_paths(prefix)?;
+        let files = self
+            .list_files()?
+            .into_iter()
+            .filter(|realpath| prefixes.iter().any(|p| is_under(realpath, p)))
+            .collect();
+        Ok(files)
+    }
+
+    /// Stores the current content of a file already in the vault as a new version.
+    pub fn update_file(&mut self, filename: impl AsRef<Path>) -> Result<String, DmError> {
+        let filename = filename.as_ref();
+        let realpath = absolute_path(filename)?;
+
+        // Check if file exists on disk
+        if !filename.exists() {
+            return Err(DmError::FileNotFound(filename.display().to_string()));
+        }
+
+        // Check if file exists in vault
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM flist WHERE realpath = ?1",
+            rusqlite::params![&realpath],
+            |row| row.get(0),
+        )?;
+
+        if count == 0 {
+            return Err(DmError::FileNotInStorage(realpath));
+        }
+
+        // Read and encrypt new content to all recipients
+        let encryption = Self::encryption(&self.conn)?;
+        let file = encrypt_file(filename, &encryption, &self.content_hash_key()?)?;
+
+        // Update record in vault and keep the new version in history
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::set_file(&tx, &realpath, &file)?;
+        Self::audit(&tx, &audit_key, "file_update", &realpath)?;
+        tx.commit()?;
+        Ok(realpath)
+    }
+
+    /// Removes a file with its history. Returns `false` if there was no such file.
+    pub fn remove_file(&mut self, filename: impl AsRef<Path>) -> Result<bool, DmError> {
+        let realpath = absolute_path(filename)?;
+
+        let audit_key = self.audit_key()?;
+
+        let tx = self.conn.transaction()?;
+        let rows_affected = tx.execute(
+            "DELETE FROM flist WHERE realpath = ?1",
+            rusqlite::params![&realpath],
+        )?;
+        tx.execute(
+            "DELETE FROM file_chunks WHERE version_id IN (
+                SELECT id FROM file_versions WHERE realpath = ?1
+             )",
+            rusqlite::params![&realpath],
+        )?;
+        tx.execute(
+            "DELETE FROM file_versions WHERE realpath = ?1",
+            rusqlite::params![&realpath],
+        )?;
+        if rows_affected > 0 {
+            Self::audit(&tx, &audit_key, "file_remove", &realpath)?;
+        }
+        tx.commit()?;
+        Ok(rows_affected > 0)
+    }
+
+    /// Decrypts the current content of a file, or the given version of it.
+    /// Use [`Vault::export_file_to`] for large files.
+    pub fn export_file(
+        &self,
+        filename: impl AsRef<Path>,
+        version: Option<i64>,
+    ) -> Result<Vec<u8>, DmError> {
+        let mut content = Vec::new();
+        self.export_file_to(filename, version, &mut content)?;
+        Ok(content)
+    }
+
+    /// Decrypts the current content of a file, or the given version of it, into `output`.
+    /// Memory use does not depend on the size of the file.
+    pub fn export_file_to(
+        &self,
+        filename: impl AsRef<Path>,
+        version: Option<i64>,
+        output: impl Write + Send,
+    ) -> Result<(), DmError> {
+        let realpath = absolute_path(filename)?;
+        self.decrypt_file_to(&realpath, version, output)?;
+        Self::audit(&self.conn, &self.audit_key()?, "file_export", &realpath)
+    }
+
+    /// [`Vault::export_file_to`] without an audit entry, for callers that log
+    /// their own operation or reveal nothing of the content.
+    pub(crate) fn decrypt_file_to(
+        &self,
+        realpath: &str,
+        version: Option<i64>,
+        output: impl Write + Send,
+    ) -> Result<(), DmError> {
+        let (id, compression) = Self::file_version_content(&self.conn, realpath, version)?;
+
+        // Ciphertext is staged in an unnamed temporary file for GPG to read
+        let ciphertext = Self::read_chunks(&self.conn, id)?;
+        let verification = self
+            .verification()?
+            .with_subject(format!("file '{}'", realpath));
+        decrypt_stream(&ciphertext, output, compression, &verification)
+    }
+
+    /// Lists stored versions of a file, oldest first.
+    pub fn file_history(&self, filename: impl AsRef<Path>) -> Result<Vec<FileVersion>, DmError> {
+        let realpath = absolute_path(filename)?;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT version, size, created_at FROM file_versions
+             WHERE realpath = ?1 ORDER BY version",
+        )?;
+        let version_iter = stmt.query_map(rusqlite::params![&realpath], |row| {
+            Ok(FileVersion {
+                version: row.get(0)?,
+                size: row.get(1)?,
+                created_at: row.get(2)?,
+                current: false,
+            })
+        })?;
+
+        let mut versions = Vec::new();
+        for version in version_iter {
+            versions.push(version?);
+        }
+
+        match versions.last_mut() {
+            Some(last) => last.current = true,
+            None => return Err(DmError::FileNotInStorage(realpath)),
+        }
+        Ok(versions)
+    }
+
+    /// Restores a previous version of a file and returns the number of the new version.
+    pub fn rollback_file(
+        &mut self,
+        filename: impl AsRef<Path>,
+        version: i64,
+    ) -> Result<i64, DmError> {
+        let realpath = absolute_path(filename)?;
+
+        let file = Self::get_file_version(&self.conn, &realpath, version)?;
+
+        // Rollback is recorded as a new version so the history stays linear
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let new_version = Self::set_file(&tx, &realpath, &file)?;
+        Self::audit(&tx, &audit_key, "file_rollback", &realpath)?;
+        tx.commit()?;
+        Ok(new_version)
+    }
+
+    /// Metadata of the current file, or the given version of it.
+    /// `None` for files stored before metadata was recorded.
+    pub fn file_metadata(
+        &self,
+        filename: impl AsRef<Path>,
+        version: Option<i64>,
+    ) -> Result<Option<FileMetadata>, DmError> {
+        let realpath = absolute_path(filename)?;
+        let version = match version {
+            Some(version) => version,
+            None => Self::current_file_version(&self.conn, &realpath)?,
+        };
+        Ok(Self::get_file_version(&self.conn, &realpath, version)?.metadata)
+    }
+
+    /// Compares tracked files under `paths`, or all tracked files when `paths` is empty,
+    /// against the disk. Untracked files found under `paths` are reported as new.
+    /// Only files vaulted before content hashes existed need to be decrypted.
+    pub fn file_status(&self, paths: &[impl AsRef<Path>]) -> Result<Vec<FileStatus>, DmError> {
+        let mut prefixes = Vec::new();
+        for path in paths {
+            prefixes.extend(prefix_paths(path)?);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT realpath, content_hash FROM flist ORDER BY realpath")?;
+        let tracked = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, Option<String>)>, _>>()?;
+
+        let mut key = None;
+        let mut statuses = Vec::new();
+        for (realpath, hash) in &tracked {
+            if !prefixes.is_empty() && !prefixes.iter().any(|p| is_under(realpath, p)) {
+                continue;
+            }
+
+            let mut disk_file = match fs::File::open(realpath) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    statuses.push(FileStatus {
+                        path: realpath.clone(),
+                        state: FileState::Missing,
+                    });
+                    continue;
+                }
+                result => result?,
+            };
+            if key.is_none() {
+                key = Some(self.content_hash_key()?);
+            }
+            let key = key.as_deref().unwrap();
+
+            let mut hasher = ContentHasher::new(key);
+            io::copy(&mut disk_file, &mut hasher)?;
+            let disk_hash = hasher.finish();
+
+            // Files stored without a hash are hashed while decrypting
+            let vault_hash = match hash {
+                Some(hash) => hash.clone(),
+                None => {
+                    let mut hasher = ContentHasher::new(key);
+                    self.decrypt_file_to(realpath, None, &mut hasher)?;
+                    hasher.finish()
+                }
+            };
+            let state = if disk_hash == vault_hash {
+                FileState::Unchanged
+            } else {
+                FileState::Modified
+            };
+            statuses.push(FileStatus {
+                path: realpath.clone(),
+                state,
+            });
+        }
+
+        for path in paths {
+            let path = path.as_ref();
+            let found = if path.is_dir() {
+                self.walk_dir(path)?
+            } else if path.is_file() {
+                vec![(path.to_path_buf(), absolute_path(path)?)]
+            } else {
+                Vec::new()
+            };
+            for (_, realpath) in found {
+                let known = tracked.iter().any(|(t, _)| *t == realpath)
+                    || statuses.iter().any(|s| s.path == realpath);
+                if !known {
+                    statuses.push(FileStatus {
+                        path: realpath,
+                        state: FileState::New,
+                    });
+                }
+            }
+        }
+
+        statuses.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(statuses)
+    }
+
+    /// Lists regular files under `dir` not matched by a [`IGNORE_FILE_NAME`] file,
+    /// with their absolute paths. The vault itself is left out.
+    fn walk_dir(&self, dir: &Path) -> Result<Vec<(PathBuf, String)>, DmError> {
+        let vault_path = absolute_dir(self.path())?;
+
+        // Walk from a clean absolute path, stored paths are derived from it
+        let mut walker = WalkBuilder::new(absolute_dir(dir)?);
+        walker
+            .standard_filters(false)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME)
+            .sort_by_file_name(|a, b| a.cmp(b));
+
+        let mut paths = Vec::new();
+        for entry in walker.build() {
+            let entry = entry.map_err(|e| DmError::InvalidInput(e.to_string()))?;
+            // Symlinks and special files are not followed
+            if !entry.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let realpath = absolute_path(entry.path())?;
+            // Never store the vault in itself
+            if realpath != vault_path {
+                paths.push((entry.into_path(), realpath));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Stores the current version of `realpath` from another vault, re-encrypted for this
+    /// one. The content hash is computed again, each vault has its own hash key.
+    pub(crate) fn import_file(
+        conn: &Connection,
+        source: &Vault,
+        realpath: &str,
+        encryption: &Encryption,
+        verification: &Verification,
+        key: &[u8],
+        audit_key: &[u8],
+    ) -> Result<(), DmError> {
+        let (id, compression) = Self::file_version_content(&source.conn, realpath, None)?;
+        let metadata = source.file_metadata(realpath, None)?;
+        let ciphertext = Self::read_chunks(&source.conn, id)?;
+
+        let mut hasher = ContentHasher::new(key);
+        let mut reencrypted = tempfile::tempfile()?;
+        encryption.reencrypt_stream(
+            &ciphertext,
+            &mut reencrypted,
+            compression,
+            verification,
+            &mut hasher,
+        )?;
+        reencrypted.rewind()?;
+
+        let file = StoredFile {
+            ciphertext: Ciphertext::File(reencrypted),
+            size: Some(hasher.size() as i64),
+            content_hash: Some(hasher.finish()),
+            metadata,
+            compression: encryption.settings.compression,
+        };
+        Self::insert_file(conn, realpath, &file)?;
+        Self::audit(conn, audit_key, "file_add", realpath)
+    }
+
+    fn insert_file(conn: &Connection, realpath: &str, file: &StoredFile) -> Result<(), DmError> {
+        conn.execute(
+            "INSERT INTO flist (realpath) VALUES (?1)",
+            rusqlite::params![realpath],
+        )?;
+        Self::set_file(conn, realpath, file)?;
+        Ok(())
+    }
+
+    /// Makes `file` the current content of `realpath` and records it as a new version.
+    fn set_file(conn: &Connection, realpath: &str, file: &StoredFile) -> Result<i64, DmError> {
+        let metadata = file.metadata.as_ref();
+        conn.execute(
+            "UPDATE flist SET content_hash = ?1, mode = ?2, uid = ?3, gid = ?4, mtime = ?5
+             WHERE realpath = ?6",
+            rusqlite::params![
+                &file.content_hash,
+                metadata.map(|m| m.mode),
+                metadata.map(|m| m.uid),
+                metadata.map(|m| m.gid),
+                metadata.map(|m| m.mtime),
+                realpath,
+            ],
+        )?;
+        Self::record_file_version(conn, realpath, file)
+    }
+
+    fn current_file_version(conn: &Connection, realpath: &str) -> Result<i64, DmError> {
+        conn.query_row(
+            "SELECT MAX(version) FROM file_versions WHERE realpath = ?1",
+            rusqlite::params![realpath],
+            |row| row.get::<_, Option<i64>>(0),
+        )?
+        .ok_or(DmError::FileNotInStorage(realpath.to_string()))
+    }
+
+    /// Row id in `file_versions` of the given or current version of a file,
+    /// with the compression its content was stored with.
+    fn file_version_content(
+        conn: &Connection,
+        realpath: &str,
+        version: Option<i64>,
+    ) -> Result<(i64, Compression), DmError> {
+        let version = match version {
+            Some(version) => version,
+            None => Self::current_file_version(conn, realpath)?,
+        };
+        let (id, compression): (i64, Option<String>) = conn
+            .query_row(
+                "SELECT id, compression FROM file_versions WHERE realpath = ?1 AND version = ?2",
+                rusqlite::params![realpath, version],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|_| DmError::FileVersionNotFound(realpath.to_string(), version))?;
+        Ok((id, Compression::from_column(compression.as_deref())?))
+    }
+
+    fn get_file_version(
+        conn: &Connection,
+        realpath: &str,
+        version: i64,
+    ) -> Result<StoredFile, DmError> {
+        let (file, compression) = conn
+            .query_row(
+            "SELECT id, size, content_hash, mode, uid, gid, mtime, compression FROM file_versions
+             WHERE realpath = ?1 AND version = ?2",
+            rusqlite::params![realpath, version],
+            |row| {
+                let metadata = match (row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?) {
+                    (Some(mode), Some(uid), Some(gid), Some(mtime)) => Some(FileMetadata {
+                        mode,
+                        uid,
+                        gid,
+                        mtime,
+                    }),
+                    _ => None,
+                };
+                let file = StoredFile {
+                    ciphertext: Ciphertext::Version(row.get(0)?),
+                    size: row.get(1)?,
+                    content_hash: row.get(2)?,
+                    metadata,
+                    compression: Compression::None,
+                };
+                Ok((file, row.get::<_, Option<String>>(7)?))
+            },
+        )
+        .map_err(|_| DmError::FileVersionNotFound(realpath.to_string(), version))?;
+        Ok(StoredFile {
+            compression: Compression::from_column(compression.as_deref())?,
+            ..file
+        })
+    }
+
+    fn record_file_version(
+        conn: &Connection,
+        realpath: &str,
+        file: &StoredFile,
+    ) -> Result<i64, DmError> {
+        let metadata = file.metadata.as_ref();
+        conn.execute(
+            "INSERT INTO file_versions
+                (realpath, version, size, content_hash, mode, uid, gid, mtime, compression)
+             SELECT ?1, COALESCE(MAX(version), 0) + 1, ?2, ?3, ?4, ?5, ?6, ?7, ?8
+             FROM file_versions WHERE realpath = ?1",
+            rusqlite::params![
+                realpath,
+                file.size,
+                &file.content_hash,
+                metadata.map(|m| m.mode),
+                metadata.map(|m| m.uid),
+                metadata.map(|m| m.gid),
+                metadata.map(|m| m.mtime),
+                file.compression.to_column(),
+            ],
+        )?;
+        let id = conn.last_insert_rowid();
+
+        match &file.ciphertext {
+            Ciphertext::File(ciphertext) => Self::write_chunks(conn, id, ciphertext)?,
+            Ciphertext::Version(source_id) => {
+                conn.execute(
+                    "INSERT INTO file_chunks (version_id, seq, data)
+                     SELECT ?1, seq, data FROM file_chunks WHERE version_id = ?2",
+                    rusqlite::params![id, source_id],
+                )?;
+            }
+        }
+
+        let version: i64 = conn.query_row(
+            "SELECT version FROM file_versions WHERE id = ?1",
+            rusqlite::params![id],
+            |row| row.get(0),
+        )?;
+        Ok(version)
+    }
+
+    /// Stores everything read from `ciphertext` as chunks of a `file_versions` row.
+    pub(crate) fn write_chunks(
+        conn: &Connection,
+        version_id: i64,
+        mut ciphertext: impl Read,
+    ) -> Result<(), DmError> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        for seq in 0.. {
+            // Fill the whole buffer unless the input ends
+            let mut len = 0;
+            while len < CHUNK_SIZE {
+                match ciphertext.read(&mut buffer[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            if len == 0 && seq > 0 {
+                break;
+            }
+            conn.execute(
+                "INSERT INTO file_chunks (version_id, seq, data) VALUES (?1, ?2, ?3)",
+                rusqlite::params![version_id, seq, &buffer[..len]],
+            )?;
+            if len < CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies the chunks of a `file_versions` row into an unnamed temporary file.
+    pub(crate) fn read_chunks(conn: &Connection, version_id: i64) -> Result<fs::File, DmError> {
+        let mut ciphertext = tempfile::tempfile()?;
+        let mut stmt =
+            conn.prepare("SELECT data FROM file_chunks WHERE version_id = ?1 ORDER BY seq")?;
+        let mut rows = stmt.query(rusqlite::params![version_id])?;
+        while let Some(row) = rows.next()? {
+            let data = row.get_ref(0)?.as_blob().map_err(rusqlite::Error::from)?;
+            ciphertext.write_all(data)?;
+        }
+        ciphertext.rewind()?;
+        Ok(ciphertext)
+    }
+}
+
+/// Replaces `path` with what `write` puts in the given file, so that readers see either
+/// the old file or the complete new one.
+///
+/// Content goes to a temporary file created with 0600 permissions next to `path`, which gets
+/// `metadata` applied, is synced and renamed over `path`. On errors the temporary file is
+/// removed; only a killed process can leave a `.dm-*` file behind.
+pub fn write_atomic(
+    path: impl AsRef<Path>,
+    metadata: Option<&FileMetadata>,
+    owner: bool,
+    write: impl FnOnce(&mut fs::File) -> Result<(), DmError>,
+) -> Result<(), DmError> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut file = tempfile::Builder::new().prefix(".dm-").tempfile_in(dir)?;
+    write(file.as_file_mut())?;
+    if let Some(metadata) = metadata {
+        metadata.apply(file.as_file(), path, owner)?;
+    }
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| DmError::IoError(e.error))?;
+
+    // Make the rename itself durable
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Encrypted file revision as stored in `file_versions`.
+struct StoredFile {
+    ciphertext: Ciphertext,
+    size: Option<i64>,
+    content_hash: Option<String>,
+    metadata: Option<FileMetadata>,
+    compression: Compression,
+}
+
+/// Where the encrypted content of a [`StoredFile`] comes from.
+enum Ciphertext {
+    /// Freshly encrypted content in a temporary file
+    File(fs::File),
+    /// Chunks of an existing `file_versions` row
+    Version(i64),
+}
+
+/// Encrypts a file from disk for storing, hashing it in the same pass.
+/// Ciphertext is kept in an unnamed temporary file, so memory use does not depend on file size.
+fn encrypt_file(path: &Path, encryption: &Encryption, key: &[u8]) -> Result<StoredFile, DmError> {
+    let metadata = FileMetadata::read(path)?;
+    let mut hasher = ContentHasher::new(key);
+    let mut ciphertext = tempfile::tempfile()?;
+
+    let plaintext = TeeReader {
+        reader: fs::File::open(path)?,
+        writer: &mut hasher,
+    };
+    encryption.encrypt_stream(plaintext, &mut ciphertext)?;
+    ciphertext.rewind()?;
+
+    Ok(StoredFile {
+        ciphertext: Ciphertext::File(ciphertext),
+        size: Some(hasher.size() as i64),
+        content_hash: Some(hasher.finish()),
+        metadata: Some(metadata),
+        compression: encryption.settings.compression,
+    })
+}
+
+/// Checks whether `realpath` is `prefix` itself or lies below it.
+fn is_under(realpath: &str, prefix: &str) -> bool {
+    let dir = prefix.trim_end_matches('/');
+    realpath == prefix
+        || realpath
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Resolves `filename` against the current directory; files are stored under this path.
+pub fn absolute_path(filename: impl AsRef<Path>) -> Result<String, DmError> {
+    let path = filename.as_ref();
+    let absolute_path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()?.join(path)
+    };
+
+    Ok(absolute_path.to_string_lossy().to_string())
+}
+
+/// [`absolute_path`] of a directory without `.` components and trailing `/`, so paths
+/// of files walked below it are the same however it was written.
+fn absolute_dir(dir: impl AsRef<Path>) -> Result<String, DmError> {
+    let dir: PathBuf = Path::new(&absolute_path(dir)?).components().collect();
+    Ok(dir.to_string_lossy().to_string())
+}
+
+/// Prefixes to look up files below `path` with: as written, which files added by
+/// name are stored under, and normalized, which walked files are stored under.
+fn prefix_paths(path: impl AsRef<Path>) -> Result<Vec<String>, DmError> {
+    let mut prefixes = vec![absolute_path(&path)?];
+    let normalized = absolute_dir(&path)?;
+    if normalized != prefixes[0] {
+        prefixes.push(normalized);
+    }
+    Ok(prefixes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::migrate;
+    use std::env;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_get_absolute_path() {
+        let temp_dir = TempDir::new().unwrap();
+        env::set_current_dir(&temp_dir).unwrap();
+
+        let relative_path = "test.txt";
+        let absolute_path = absolute_path(relative_path).unwrap();
+
+        assert!(absolute_path.contains("test.txt"));
+        assert!(Path::new(&absolute_path).is_absolute());
+    }
+
+    #[test]
+    fn test_file_metadata_apply() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("id_ed25519");
+        fs::write(&path, b"key").unwrap();
+
+        let mut metadata = FileMetadata::read(&path).unwrap();
+        metadata.mode = 0o600;
+        metadata.mtime = 1_700_000_000;
+        metadata
+            .apply(&fs::File::open(&path).unwrap(), &path, false)
+            .unwrap();
+        assert_eq!(FileMetadata::read(&path).unwrap(), metadata);
+
+        // Modes without read permission can be restored too
+        metadata.mode = 0o200;
+        write_atomic(&path, Some(&metadata), false, |file| {
+            file.write_all(b"new key")?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(FileMetadata::read(&path).unwrap(), metadata);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("config");
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_atomic(&path, None, false, |file| Ok(file.write_all(b"new")?)).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(FileMetadata::read(&path).unwrap().mode, 0o600);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+
+        let content: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|i| i as u8).collect();
+        Vault::write_chunks(&conn, 1, &content[..]).unwrap();
+        Vault::write_chunks(&conn, 2, &b""[..]).unwrap();
+
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM file_chunks WHERE version_id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 3);
+
+        let mut read = Vec::new();
+        Vault::read_chunks(&conn, 1)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, content);
+
+        read.clear();
+        Vault::read_chunks(&conn, 2)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read.is_empty());
+    }
+
+    #[test]
+    fn test_is_under() {
+        assert!(is_under("/etc/app/conf", "/etc/app"));
+        assert!(is_under("/etc/app/conf", "/etc/app/"));
+        assert!(is_under("/etc/app", "/etc/app"));
+        assert!(!is_under("/etc/application", "/etc/app"));
+        assert_eq!(
+            prefix_paths("/etc/./app/").unwrap(),
+            ["/etc/./app/", "/etc/app"]
+        );
+    }
+
+    #[test]
+    fn test_record_file_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE flist (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                realpath TEXT NOT NULL UNIQUE,
+                body BLOB NOT NULL
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO flist (realpath, body) VALUES ('/etc/legacy', x'00')",
+            [],
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        // Existing files are backfilled as version 1
+        let stored = Vault::get_file_version(&conn, "/etc/legacy", 1).unwrap();
+        assert_eq!(stored.size, None);
+        assert_eq!(stored.content_hash, None);
+        assert_eq!(stored.metadata, None);
+
+        let file = StoredFile {
+            ciphertext: stored.ciphertext,
+            size: Some(2),
+            content_hash: Some("ab".to_string()),
+            metadata: Some(FileMetadata {
+                mode: 0o600,
+                uid: 1000,
+                gid: 1000,
+                mtime: 1_700_000_000,
+            }),
+            compression: Compression::Zstd,
+        };
+        assert_eq!(
+            Vault::record_file_version(&conn, "/etc/legacy", &file).unwrap(),
+            2
+        );
+        let (id, compression) = Vault::file_version_content(&conn, "/etc/legacy", None).unwrap();
+        assert_eq!(compression, Compression::Zstd);
+        let mut ciphertext = Vec::new();
+        Vault::read_chunks(&conn, id)
+            .unwrap()
+            .read_to_end(&mut ciphertext)
+            .unwrap();
+        assert_eq!(ciphertext, b"\x00");
+        assert_eq!(
+            Vault::get_file_version(&conn, "/etc/legacy", 2)
+                .unwrap()
+                .metadata,
+            file.metadata
+        );
+        assert_eq!(
+            Vault::record_file_version(&conn, "/etc/other", &file).unwrap(),
+            1
+        );
+        assert!(matches!(
+            Vault::get_file_version(&conn, "/etc/other", 2),
+            Err(DmError::FileVersionNotFound(_, 2))
+        ));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::crypto::decrypt_unverified;
+use crate::error::DmError;
+use crate::records::SecretRecord;
+use crate::vault::Vault;
+use ignore::WalkBuilder;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// What to do with imported secrets that are already in the vault.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the vault's secret
+    #[default]
+    Skip,
+    /// Store the imported value as a new version
+    Overwrite,
+    /// Import nothing
+    Fail,
+}
+
+/// Outcome of an import, or what it would do in a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    pub overwritten: Vec<String>,
+    /// Secrets already in the vault and kept as they are
+    pub skipped: Vec<String>,
+}
+
+/// A secret to import, named and tagged after its location in a pass store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PassEntry {
+    path: PathBuf,
+    name: String,
+    tags: Vec<String>,
+}
+
+impl Vault {
+    /// Imports the entries of a pass password store. Each `.gpg` file becomes a secret
+    /// named after its path in the store, without extension, and tagged with its
+    /// directories. Entries are decrypted with the local keyring. A single trailing
+    /// newline, which pass adds, is removed. Either all entries are imported or none.
+    /// With `dry_run` nothing is decrypted or written.
+    pub fn import_pass(
+        &mut self,
+        dir: impl AsRef<Path>,
+        conflicts: ConflictPolicy,
+        dry_run: bool,
+    ) -> Result<ImportReport, DmError> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(DmError::FileNotFound(dir.display().to_string()));
+        }
+
+        let (report, entries) =
+            Self::plan_import(&self.conn, pass_entries(dir)?, |e| &e.name, conflicts)?;
+        if dry_run {
+            return Ok(report);
+        }
+
+        // Decrypt everything first, so a missing key leaves the vault untouched
+        let mut secrets = Vec::new();
+        for entry in entries {
+            let mut value = decrypt_unverified(fs::File::open(&entry.path)?)?;
+            if value.last() == Some(&b'\n') {
+                value.pop();
+            }
+            secrets.push((entry.name, value, entry.tags.join(",")));
+        }
+
+        let target = dir.display().to_string();
+        self.store_imported(secrets, &report, "import_pass", &target)?;
+        Ok(report)
+    }
+
+    /// Imports secret records, e.g. read with [`parse_records`](crate::parse_records).
+    /// Tags of overwritten secrets are kept when a record has none. Either all records
+    /// are imported or none. With `dry_run` nothing is written.
+    pub fn import_secrets(
+        &mut self,
+        records: Vec<SecretRecord>,
+        conflicts: ConflictPolicy,
+        dry_run: bool,
+    ) -> Result<ImportReport, DmError> {
+        let mut names = HashSet::new();
+        for record in &records {
+            if record.name.is_empty() {
+                return Err(DmError::InvalidInput("Secret name is empty".to_string()));
+            }
+            if !names.insert(record.name.as_str()) {
+                return Err(DmError::InvalidInput(format!(
+                    "Secret '{}' is listed more than once",
+                    record.name
+                )));
+            }
+        }
+
+        let (report, records) = Self::plan_import(&self.conn, records, |r| &r.name, conflicts)?;
+        if dry_run {
+            return Ok(report);
+        }
+
+        let secrets = records
+            .into_iter()
+            .map(|r| (r.name, r.value.into_bytes(), r.tags.join(",")))
+            .collect();
+        let target = format!("{} secrets", report.added.len() + report.overwritten.len());
+        self.store_imported(secrets, &report, "import_secrets", &target)?;
+        Ok(report)
+    }
+
+    /// Sorts entries into added, overwritten and skipped ones and returns those to store.
+    fn plan_import<T>(
+        conn: &Connection,
+        entries: Vec<T>,
+        name: impl Fn(&T) -> &String,
+        conflicts: ConflictPolicy,
+    ) -> Result<(ImportReport, Vec<T>), DmError> {
+        let mut report = ImportReport::default();
+        let mut imports = Vec::new();
+        for entry in entries {
+            let name = name(&entry).clone();
+            if !Self::secret_exists(conn, &name)? {
+                report.added.push(name);
+            } else {
+                match conflicts {
+                    ConflictPolicy::Skip => {
+                        report.skipped.push(name);
+                        continue;
+                    }
+                    ConflictPolicy::Overwrite => report.overwritten.push(name),
+                    ConflictPolicy::Fail => return Err(DmError::SecretAlreadyExists(name)),
+                }
+            }
+            imports.push(entry);
+        }
+        Ok((report, imports))
+    }
+
+    /// Stores `(name, value, tags)` of planned imports in one transaction.
+    fn store_imported(
+        &mut self,
+        secrets: Vec<(String, Vec<u8>, String)>,
+        report: &ImportReport,
+        operation: &str,
+        target: &str,
+    ) -> Result<(), DmError> {
+        let encryption = Self::encryption(&self.conn)?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        for (name, value, tags) in secrets {
+            if report.overwritten.contains(&name) {
+                Self::replace_secret(&tx, &encryption, &audit_key, &name, &value, &tags)?;
+            } else {
+                Self::insert_secret(&tx, &encryption, &audit_key, &name, &value, &tags)?;
+            }
+        }
+        Self::audit(&tx, &audit_key, operation, target)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub(crate) fn secret_exists(conn: &Connection, name: &str) -> Result<bool, DmError> {
+        let exists = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM secrets WHERE name = ?1)",
+            rusqlite::params![name],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
+}
+
+/// Finds the entries of a pass store sorted by name. Hidden files and directories,
+/// such as `.gpg-id` and `.git`, are skipped.
+fn pass_entries(dir: &Path) -> Result<Vec<PassEntry>, DmError> {
+    let mut walker = WalkBuilder::new(dir);
+    walker
+        .standard_filters(false)
+        .hidden(true)
+        .sort_by_file_name(|a, b| a.cmp(b));
+
+    let mut entries = Vec::new();
+    for entry in walker.build() {
+        let entry = entry.map_err(|e| DmError::InvalidInput(e.to_string()))?;
+        let path = entry.path();
+        if !entry.file_type().is_some_and(|t| t.is_file())
+            || path.extension().is_none_or(|ext| ext != "gpg")
+        {
+            continue;
+        }
+
+        let relative = path.strip_prefix(dir).unwrap_or(path).with_extension("");
+        let mut components = Vec::new();
+        for component in relative.components() {
+            if let Component::Normal(component) = component {
+                let component = component.to_str().ok_or_else(|| {
+                    DmError::InvalidInput(format!("'{}' is not valid UTF-8", path.display()))
+                })?;
+                components.push(component.to_string());
+            }
+        }
+
+        let name = components.join("/");
+        components.pop();
+        entries.push(PassEntry {
+            path: path.to_path_buf(),
+            name,
+            tags: components,
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_entries() {
+        let store = tempfile::tempdir().unwrap();
+        for file in [
+            "github.gpg",
+            "work/aws/access-key.gpg",
+            "work/notes.txt",
+            ".gpg-id",
+            ".git/objects/ab.gpg",
+        ] {
+            let path = store.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let entries = pass_entries(store.path()).unwrap();
+        let names: Vec<_> = entries
+            .iter()
+            .map(|e| (e.name.as_str(), e.tags.join(",")))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("github", String::new()),
+                ("work/aws/access-key", "work,aws".to_string())
+            ]
+        );
+        assert_eq!(
+            entries[1].path,
+            store.path().join("work/aws/access-key.gpg")
+        );
+    }
+}
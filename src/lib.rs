@@ -0,0 +1,51 @@
+//! Dark matter - simple vault with GPG encryption.
+//!
+//! A vault is a single SQLite database holding GPG encrypted secrets and
+//! files. Open one with [`Vault::open`] or create it with [`Vault::init`].
+
+mod audit;
+mod backup;
+mod check;
+mod crypto;
+mod diff;
+mod env;
+mod error;
+mod files;
+mod import;
+mod records;
+mod schema;
+mod secrets;
+mod settings;
+mod template;
+mod vault;
+
+pub use audit::{AuditEntry, AuditVerification};
+pub use backup::{Backup, MergedEntries};
+pub use check::{CheckReport, EntryCheck};
+pub use crypto::{
+    diagnose_key, verify_key, EncryptionTest, KeyDiagnostics, SubkeyInfo, UserIdInfo,
+};
+pub use diff::{ContentSummary, FileDiff};
+pub use env::{env_var_name, env_vars, format_env, EnvFormat};
+pub use error::DmError;
+pub use files::{
+    absolute_path, write_atomic, AddedFiles, FileMetadata, FileState, FileStatus, FileVersion,
+    IGNORE_FILE_NAME,
+};
+pub use import::{ConflictPolicy, ImportReport};
+pub use records::{format_records, parse_records, RecordFormat, SecretRecord};
+pub use schema::SCHEMA_VERSION;
+pub use secrets::{SecretInfo, SecretVersion};
+pub use settings::{Compression, SignaturePolicy, VaultSettings};
+pub use vault::Vault;
+
+/// Default file name of a vault database.
+pub const DB_NAME: &str = "dm-vault.db";
+
+pub(crate) const GPG_KEY_HASH_CONFIG: &str = "gpg_key_hash";
+pub(crate) const SECRET_HISTORY_LIMIT_CONFIG: &str = "secret_history_limit";
+pub(crate) const SCHEMA_VERSION_CONFIG: &str = "schema_version";
+pub(crate) const CONTENT_HASH_KEY_CONFIG: &str = "content_hash_key";
+pub(crate) const ARMOR_CONFIG: &str = "armor";
+pub(crate) const COMPRESSION_CONFIG: &str = "compression";
+pub(crate) const SIGNATURES_CONFIG: &str = "signatures";
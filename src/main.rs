@@ -1,25 +1,88 @@
-use clap::{Parser, Subcommand};
-use gpgme::{Context, Protocol};
-use rusqlite::Connection;
+use base64::Engine;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use dark_matter::{
+    Compression, ConflictPolicy, DmError, EncryptionTest, EnvFormat, FileDiff, FileState,
+    RecordFormat, SignaturePolicy, Vault, VaultSettings, DB_NAME,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::ffi::OsString;
 use std::fs;
-use std::io::{self, Write};
-use std::path::Path;
-
-const DB_NAME: &str = "dm-vault.db";
-const GPG_KEY_HASH_CONFIG: &str = "gpg_key_hash";
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 #[derive(Parser)]
 #[command(name = "dark-matter")]
 #[command(about = "Dark matter - simple vault CLI utility with GPG encryption")]
 #[command(version = "1.0.0")]
 struct Cli {
+    /// Path to vault database or directory containing it.
+    /// By default dm-vault.db is searched in current and parent directories.
+    #[arg(long, global = true, env = "DARK_MATTER_VAULT")]
+    vault: Option<PathBuf>,
+
+    /// Only accept content signed by these GPG keys instead of the vault key.
+    /// Comma-separated. Set it outside the vault, e.g. in CI configuration.
+    #[arg(
+        long,
+        global = true,
+        env = "DARK_MATTER_SIGNERS",
+        value_delimiter = ',',
+        value_name = "KEY_HASHES"
+    )]
+    signers: Vec<String>,
+
+    /// What to do with content not signed by an allowed signer, instead of the vault
+    /// setting. Set it outside the vault, e.g. in CI configuration.
+    #[arg(
+        long,
+        global = true,
+        env = "DARK_MATTER_SIGNATURES",
+        value_enum,
+        value_name = "POLICY"
+    )]
+    signature_policy: Option<SignaturesArg>,
+
+    /// Output format
+    #[arg(
+        id = "output_format",
+        long = "format",
+        value_name = "FORMAT",
+        global = true,
+        value_enum,
+        default_value_t = OutputFormat::Text
+    )]
+    format: OutputFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// JSON objects with stable field names
+    Json,
+}
+
+// Commands with their own --format take the output format as --output-format.
+// Sharing the id keeps clap from also adding the global --format to them.
+fn output_format_arg() -> clap::Arg {
+    clap::Arg::new("output_format")
+        .long("output-format")
+        .value_name("FORMAT")
+        .value_parser(clap::value_parser!(OutputFormat))
+        .default_value("text")
+        .help("Output format, --format selects the file format here")
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Init new vault in current directory
+    /// Init new vault in current directory or at --vault path
     Init {
         /// Hash GPG key for encryption
         key_hash: String,
@@ -39,6 +102,191 @@ pub enum Commands {
         #[command(subcommand)]
         action: KeysCommands,
     },
+    /// Manage GPG keys the vault is encrypted to
+    Recipients {
+        #[command(subcommand)]
+        action: RecipientsCommands,
+    },
+    /// Show and verify the log of vault operations
+    Audit {
+        #[command(subcommand)]
+        action: AuditCommands,
+    },
+    /// Vault settings and maintenance
+    Vault {
+        #[command(subcommand)]
+        action: VaultCommands,
+    },
+    /// Import secrets from other password managers
+    Import {
+        #[command(subcommand)]
+        action: ImportCommands,
+    },
+    /// Render template replacing {{ secret "name" }} placeholders with secret values
+    Render {
+        /// Template file
+        template: PathBuf,
+        /// File to write rendered output to, created with 0600 permissions
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Also store rendered output in vault as a file
+        #[arg(long)]
+        store: bool,
+    },
+    /// Run command with secrets set as environment variables
+    Run {
+        /// Only pass secrets with any of these tags. Comma-separated.
+        #[arg(short, long, default_value = "")]
+        tags: String,
+        /// Command to run with its arguments, after --
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommands {
+    /// List recorded operations, oldest first
+    List {
+        /// Only show this many of the newest entries
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+    },
+    /// Check that no entries were edited or removed
+    Verify,
+}
+
+#[derive(Subcommand)]
+pub enum ImportCommands {
+    /// Import entries of a pass password store, named after their path and tagged
+    /// with their directories
+    Pass {
+        /// Password store directory, ~/.password-store by default
+        #[arg(env = "PASSWORD_STORE_DIR")]
+        dir: Option<PathBuf>,
+        /// Only report what would be imported, without decrypting anything
+        #[arg(long)]
+        dry_run: bool,
+        /// What to do with secrets already in the vault
+        #[arg(long, value_enum, default_value_t = ConflictArg::Skip)]
+        on_conflict: ConflictArg,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConflictArg {
+    /// Keep the vault's secret
+    Skip,
+    /// Store the imported value as a new version
+    Overwrite,
+    /// Import nothing
+    Fail,
+}
+
+impl From<ConflictArg> for ConflictPolicy {
+    fn from(conflict: ConflictArg) -> Self {
+        match conflict {
+            ConflictArg::Skip => ConflictPolicy::Skip,
+            ConflictArg::Overwrite => ConflictPolicy::Overwrite,
+            ConflictArg::Fail => ConflictPolicy::Fail,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum VaultCommands {
+    /// Show or change how new secrets and files are stored
+    Settings {
+        /// Store ciphertext as ASCII armor (true) or binary OpenPGP (false)
+        #[arg(long)]
+        armor: Option<bool>,
+        /// Compress content before encrypting it
+        #[arg(long, value_enum)]
+        compression: Option<CompressionArg>,
+        /// What to do with content not signed by an allowed signer
+        #[arg(long, value_enum)]
+        signatures: Option<SignaturesArg>,
+    },
+    /// Rewrite all secrets and files with current settings and shrink vault file
+    Compact,
+    /// Check database integrity and that every secret and file decrypts
+    Check {
+        /// Only parse OpenPGP headers instead of decrypting, needs no secret key
+        #[arg(long)]
+        headers_only: bool,
+    },
+    /// Write a consistent copy of the vault to a file
+    Backup {
+        /// Backup file to write
+        output: PathBuf,
+        /// Encrypt and sign the backup for the vault recipients
+        #[arg(long)]
+        encrypt: bool,
+    },
+    /// Replace the vault with a backup, or add entries missing from it
+    Restore {
+        /// Backup file made by 'vault backup'
+        input: PathBuf,
+        /// Only add secrets and files not in the vault, keep everything else
+        #[arg(long)]
+        merge: bool,
+        /// Replace an existing vault without asking
+        #[arg(short = 'y', long = "yes", default_value_t = false)]
+        confirm: bool,
+        /// Replace even if the backup relaxes signature checks or adds recipients
+        #[arg(long, conflicts_with = "merge")]
+        trust_settings: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompressionArg {
+    /// Store content as is
+    None,
+    /// Compress with zstd before encrypting
+    Zstd,
+}
+
+impl From<CompressionArg> for Compression {
+    fn from(compression: CompressionArg) -> Self {
+        match compression {
+            CompressionArg::None => Compression::None,
+            CompressionArg::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SignaturesArg {
+    /// Refuse it
+    Require,
+    /// Print a warning and use it
+    Warn,
+}
+
+impl From<SignaturesArg> for SignaturePolicy {
+    fn from(signatures: SignaturesArg) -> Self {
+        match signatures {
+            SignaturesArg::Require => SignaturePolicy::Require,
+            SignaturesArg::Warn => SignaturePolicy::Warn,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum RecipientsCommands {
+    /// Add recipient and re-encrypt vault to all recipients
+    Add {
+        /// Hash of GPG key to add
+        key_hash: String,
+    },
+    /// Remove recipient and re-encrypt vault to remaining recipients
+    Remove {
+        /// Hash of GPG key to remove
+        key_hash: String,
+    },
+    /// List all recipients of vault
+    List,
 }
 
 #[derive(Subcommand)]
@@ -47,8 +295,8 @@ pub enum SecretsCommands {
     Add {
         /// Name of the secret
         name: String,
-        /// New value for the secret
-        value: String,
+        #[command(flatten)]
+        value: SecretValueArgs,
         /// Optional tags for the secret. Comma-separated.
         #[arg(short, long, default_value = "")]
         tags: String,
@@ -63,8 +311,8 @@ pub enum SecretsCommands {
     Update {
         /// Name of the secret to update
         name: String,
-        /// New value for the secret
-        value: String,
+        #[command(flatten)]
+        value: SecretValueArgs,
         /// Optional tags for the secret. Comma-separated.
         #[arg(short, long, default_value = "")]
         tags: String,
@@ -78,7 +326,125 @@ pub enum SecretsCommands {
     Show {
         /// Name of the secret to show
         name: String,
+        /// Show a specific version instead of the current one
+        #[arg(long)]
+        version: Option<i64>,
+    },
+    /// Show version history of secret in vault
+    History {
+        /// Name of the secret
+        name: String,
     },
+    /// Restore a previous version of secret in vault
+    Rollback {
+        /// Name of the secret to roll back
+        name: String,
+        /// Version to restore
+        version: i64,
+    },
+    /// Show or set how many versions of each secret are kept
+    Retention {
+        /// Number of versions to keep per secret, 0 keeps all
+        count: Option<i64>,
+    },
+    /// Print secrets as environment variable assignments, or all their fields as
+    /// CSV, JSON or YAML
+    #[command(arg(output_format_arg()))]
+    Export {
+        /// Export format
+        #[arg(long, value_enum, default_value_t = ExportFormat::Dotenv)]
+        format: ExportFormat,
+        /// Only export secrets with any of these tags. Comma-separated.
+        #[arg(short, long, default_value = "")]
+        tags: String,
+        /// Confirm printing every value in plain text, needed for csv, json and yaml
+        #[arg(long)]
+        plaintext: bool,
+    },
+    /// Add secrets from a CSV, JSON or YAML file with name, value and tags fields
+    #[command(arg(output_format_arg()))]
+    Import {
+        /// File to read, - for standard input
+        file: PathBuf,
+        /// Input format
+        #[arg(long, value_enum)]
+        format: RecordFormatArg,
+        /// Only report what would be imported
+        #[arg(long)]
+        dry_run: bool,
+        /// What to do with secrets already in the vault
+        #[arg(long, value_enum, default_value_t = ConflictArg::Skip)]
+        on_conflict: ConflictArg,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// NAME='value' lines for .env files
+    Dotenv,
+    /// export NAME='value' lines to source in a shell
+    Shell,
+    /// NAME=value lines for docker run --env-file
+    DockerEnv,
+    /// name,value,tags,version,updated_at rows
+    Csv,
+    /// Array of secret objects
+    Json,
+    /// Sequence of secret mappings
+    Yaml,
+}
+
+// Variable assignments or records with all fields of each secret
+enum ExportTarget {
+    Env(EnvFormat),
+    Records(RecordFormat),
+}
+
+impl From<ExportFormat> for ExportTarget {
+    fn from(format: ExportFormat) -> Self {
+        match format {
+            ExportFormat::Dotenv => ExportTarget::Env(EnvFormat::Dotenv),
+            ExportFormat::Shell => ExportTarget::Env(EnvFormat::Shell),
+            ExportFormat::DockerEnv => ExportTarget::Env(EnvFormat::DockerEnv),
+            ExportFormat::Csv => ExportTarget::Records(RecordFormat::Csv),
+            ExportFormat::Json => ExportTarget::Records(RecordFormat::Json),
+            ExportFormat::Yaml => ExportTarget::Records(RecordFormat::Yaml),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RecordFormatArg {
+    /// Rows with name, value and optional tags columns, tags comma-separated
+    Csv,
+    /// Array of objects with name, value and optional tags fields
+    Json,
+    /// Sequence of mappings with name, value and optional tags fields
+    Yaml,
+}
+
+impl From<RecordFormatArg> for RecordFormat {
+    fn from(format: RecordFormatArg) -> Self {
+        match format {
+            RecordFormatArg::Csv => RecordFormat::Csv,
+            RecordFormatArg::Json => RecordFormat::Json,
+            RecordFormatArg::Yaml => RecordFormat::Yaml,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct SecretValueArgs {
+    /// New value for the secret. Prompted without echo when omitted.
+    /// Prefer --stdin or --from-file, values given here end up in shell history.
+    value: Option<String>,
+    /// Read value from standard input. One trailing newline (\n or \r\n) is removed,
+    /// use --from-file for binary content.
+    #[arg(long, conflicts_with_all = ["value", "from_file"])]
+    stdin: bool,
+    /// Read value from file, binary content is kept as is
+    #[arg(long, value_name = "PATH", conflicts_with = "value")]
+    from_file: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -88,6 +454,11 @@ pub enum KeysCommands {
         /// Hash of GPG key to validate
         key_hash: String,
     },
+    /// Replace vault key and re-encrypt all secrets and files to it
+    Rotate {
+        /// Hash of new GPG key
+        new_key_hash: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -96,6 +467,10 @@ pub enum FileCommands {
     Add {
         /// Absolute path to file for adding
         filename: String,
+
+        /// Add all files in directory, skipping those matched by .dmignore files
+        #[arg(short = 'R', long, default_value_t = false)]
+        recursive: bool,
     },
     /// List all files in vault
     List,
@@ -114,768 +489,1120 @@ pub enum FileCommands {
         /// Absolute path to file for exporting
         filename: String,
 
+        /// Export a specific version instead of the current one
+        #[arg(long, conflicts_with = "recursive")]
+        version: Option<i64>,
+
         /// Export to current directory
         #[arg(short, long, default_value_t = false)]
         relative: bool,
 
+        /// Export all files stored under directory
+        #[arg(short = 'R', long, default_value_t = false)]
+        recursive: bool,
+
+        /// Do not restore owner and group, which needs root
+        #[arg(long, default_value_t = false)]
+        no_owner: bool,
+
         /// Export to current directory
         #[arg(short = 'y', long = "yes", default_value_t = false)]
         confirm: bool,
     },
+    /// Show version history of file in vault
+    History {
+        /// Absolute path to file
+        filename: String,
+    },
+    /// Show differences between file in vault and file on disk
+    Diff {
+        /// Absolute path to file
+        filename: String,
+        /// Compare a specific version instead of the current one
+        #[arg(long)]
+        version: Option<i64>,
+    },
+    /// Compare files in vault with files on disk
+    Status {
+        /// Files or directories to check, all files in vault by default.
+        /// Untracked files found here are reported as new.
+        paths: Vec<PathBuf>,
+    },
+    /// Restore a previous version of file in vault
+    Rollback {
+        /// Absolute path to file for rollback
+        filename: String,
+        /// Version to restore
+        version: i64,
+    },
 }
 
-#[derive(Debug)]
-enum DmError {
-    DatabaseNotFound,
-    DatabaseAlreadyExists,
-    FileNotFound(String),
-    GpgKeyNotFound(String),
-    FileAlreadyExists(String),
-    FileNotInStorage(String),
-    SecretNotInStorage(String),
-    DatabaseError(rusqlite::Error),
-    GpgError(gpgme::Error),
-    IoError(io::Error),
+struct App {
+    vault: Option<PathBuf>,
+    signers: Vec<String>,
+    signature_policy: Option<SignaturePolicy>,
+    format: OutputFormat,
 }
 
-impl std::fmt::Display for DmError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            DmError::DatabaseNotFound => {
-                write!(f, "Error: database dm-vault.db not found. Please run 'dm init <gpg_key_hash>' to create a new vault.")
-            }
-            DmError::DatabaseAlreadyExists => write!(f, "Error: database dm-vault.db already exists. Please remove it or use a different directory."),
-            DmError::FileNotFound(path) => write!(f, "Error: File '{}' not found", path),
-            DmError::GpgKeyNotFound(hash) => {
-                write!(f, "Error: GPG key '{}' not found", hash)
-            }
-            DmError::SecretNotInStorage(name) => {
-                write!(f, "Error: Secret '{}' not found in vault", name)
-            }
-            DmError::FileAlreadyExists(path) => write!(
-                f,
-                "Error: File '{}' already exists in vault. Use 'dm update <filename>' to update it.",
-                path
-            ),
-            DmError::FileNotInStorage(path) => {
-                write!(f, "Error: File '{}' not found in vault", path)
-            }
-            DmError::DatabaseError(e) => write!(f, "Database error: {}", e),
-            DmError::GpgError(e) => write!(f, "GPG error: {}", e),
-            DmError::IoError(e) => write!(f, "IO error: {}", e),
+impl App {
+    fn open_vault(&self) -> Result<Vault, DmError> {
+        let mut vault = match &self.vault {
+            Some(path) => Vault::open(vault_file(path))?,
+            None => Vault::open(Vault::discover(std::env::current_dir()?)?)?,
+        };
+        if !self.signers.is_empty() {
+            vault.set_allowed_signers(self.signers.clone());
         }
+        if let Some(policy) = self.signature_policy {
+            vault.set_signature_policy(policy);
+        }
+        vault.set_warning_handler(|message| eprintln!("Warning: {}", message));
+        Ok(vault)
     }
-}
 
-impl std::error::Error for DmError {}
-
-impl From<rusqlite::Error> for DmError {
-    fn from(error: rusqlite::Error) -> Self {
-        DmError::DatabaseError(error)
+    // Print `value` as JSON, or call `text` to print it for humans
+    fn emit<T: Serialize>(&self, value: T, text: impl FnOnce()) -> Result<(), DmError> {
+        match self.format {
+            OutputFormat::Text => text(),
+            OutputFormat::Json => {
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer_pretty(&mut stdout, &value).map_err(io::Error::from)?;
+                writeln!(stdout)?;
+            }
+        }
+        Ok(())
     }
-}
 
-impl From<gpgme::Error> for DmError {
-    fn from(error: gpgme::Error) -> Self {
-        DmError::GpgError(error)
+    fn report_error(&self, error: &DmError) {
+        match self.format {
+            OutputFormat::Text => {
+                eprintln!("{}", error);
+                print_error_hints(error);
+            }
+            OutputFormat::Json => {
+                let value = json!({
+                    "error": {
+                        "code": error.code(),
+                        "message": error.to_string(),
+                    }
+                });
+                eprintln!("{}", value);
+            }
+        }
     }
 }
 
-impl From<io::Error> for DmError {
-    fn from(error: io::Error) -> Self {
-        DmError::IoError(error)
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "✅ Yes"
+    } else {
+        "❌ No"
     }
 }
 
-struct DataManager;
-
-impl DataManager {
-    fn init(key_hash: &str) -> Result<(), DmError> {
-        // Check if database already exists
-        if Path::new(DB_NAME).exists() {
-            return Err(DmError::DatabaseAlreadyExists);
+fn diagnose_key(app: &App, key_hash: &str) -> Result<(), DmError> {
+    let diagnostics = match dark_matter::diagnose_key(key_hash) {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => {
+            if app.format == OutputFormat::Text {
+                println!("❌ GPG key not found: {}", e);
+                println!("\nDiagnosis:");
+                println!("1. Check the hash: {}", key_hash);
+                println!("2. Check available keys:");
+                println!("   $ gpg --list-keys");
+                println!("3. Maybe you need to import the key:");
+                println!("   $ gpg --import path/to/key.asc");
+            }
+            return Err(e);
         }
+    };
 
-        // Check if GPG key exists
-        Self::verify_gpg_key(key_hash)?;
-
-        // Create vault
-        let conn = Connection::open(DB_NAME)?;
-
-        conn.execute(
-            "CREATE TABLE config (
-                key TEXT PRIMARY KEY,
-                value TEXT NOT NULL
-            )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE secrets (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL UNIQUE,
-                body BLOB NOT NULL,
-                tags TEXT DEFAULT ''
-            )",
-            [],
-        )?;
-
-        conn.execute(
-            "CREATE TABLE flist (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                realpath TEXT NOT NULL UNIQUE,
-                body BLOB NOT NULL
-            )",
-            [],
-        )?;
-
-        // Save hash of GPG key in configuration
-        conn.execute(
-            "INSERT INTO config (key, value) VALUES (?1, ?2)",
-            rusqlite::params![GPG_KEY_HASH_CONFIG, key_hash],
-        )?;
-
-        println!("Vault initialized with GPG key: {}", key_hash);
-        Ok(())
-    }
-
-    // secrets management methods
-    fn add_secret(name: &str, value: &str, tags: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        // Check if secret already exists
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(id) FROM secrets WHERE name = ?1",
-            rusqlite::params![name],
-            |row| row.get(0),
-        )?;
-
-        if count > 0 {
-            return Err(DmError::FileAlreadyExists(name.to_string()));
+    let value = json!({
+        "key_hash": key_hash,
+        "suitable": diagnostics.can_encrypt,
+        "key": &diagnostics,
+    });
+    app.emit(value, || {
+        println!("✅ GPG key found in keyring");
+
+        // Check key capabilities
+        println!("\nKey capabilities:");
+        println!("  - Encryption: {}", yes_no(diagnostics.can_encrypt));
+        println!("  - Signing: {}", yes_no(diagnostics.can_sign));
+        println!("  - Certification: {}", yes_no(diagnostics.can_certify));
+        println!(
+            "  - Authentication: {}",
+            yes_no(diagnostics.can_authenticate)
+        );
+
+        // Display key details
+        println!("\nDetails:");
+        println!("  - ID: {}", diagnostics.id.as_deref().unwrap_or("Unknown"));
+        println!(
+            "  - Fingerprint: {}",
+            diagnostics.fingerprint.as_deref().unwrap_or("Unknown")
+        );
+
+        println!("Subkeys ({}):", diagnostics.subkeys.len());
+        for (i, subkey) in diagnostics.subkeys.iter().enumerate() {
+            println!("  Subkey #{}", i + 1);
+            println!("    - ID: {}", subkey.id.as_deref().unwrap_or("Unknown"));
+            println!("    - Can encrypt: {}", yes_no(subkey.can_encrypt));
         }
 
-        // Encrypt the value
-        let key_hash = Self::get_gpg_key_hash(&conn)?;
-        let encrypted_value = Self::encrypt_content(value.as_bytes(), &key_hash)?;
-
-        // Insert into database
-        conn.execute(
-            "INSERT INTO secrets (name, body, tags) VALUES (?1, ?2, ?3)",
-            rusqlite::params![name, encrypted_value, tags],
-        )?;
-
-        println!("Secret '{}' successfully added", name);
-        Ok(())
-    }
+        println!("\nUser IDs ({}):", diagnostics.user_ids.len());
+        for (i, uid) in diagnostics.user_ids.iter().enumerate() {
+            println!("  ID #{}", i + 1);
+            println!("    - Name: {}", uid.name.as_deref().unwrap_or("Unknown"));
+            println!("    - Email: {}", uid.email.as_deref().unwrap_or("Unknown"));
+        }
 
-    fn list_secrets(tags: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        let mut stmt = conn.prepare("SELECT name, tags FROM secrets ORDER BY name")?;
-        let secret_iter = stmt.query_map([], |row| {
-            let name: String = row.get(0)?;
-            let tags: String = row.get(1)?;
-            Ok((name, tags))
-        })?;
-
-        let mut secrets = Vec::new();
-        for secret in secret_iter {
-            let secret: (String, String) = secret?;
-            if !tags.is_empty() {
-                // Filter by tags if specified
-                if !secret
-                    .1
-                    .split(',')
-                    .any(|t| tags.split(',').any(|tag| tag.trim() == t.trim()))
-                {
-                    continue;
-                }
+        match &diagnostics.encryption_test {
+            EncryptionTest::Passed => {
+                println!("\nEncryption testing:");
+                println!("  ✅ Encryption successful");
+            }
+            EncryptionTest::Failed(e) => {
+                println!("\nEncryption testing:");
+                println!("  ❌ Encryption failed: {}", e);
+            }
+            EncryptionTest::Skipped => {
+                println!("\nEncryption testing: ❌ Skipped (key does not support encryption)");
             }
-            secrets.push(secret);
         }
 
-        if secrets.is_empty() {
-            println!("No secrets found in vault");
+        // Additional diagnostics and recommendations
+        if !diagnostics.can_encrypt {
+            println!("\n❌ Problem: Key cannot be used for encryption");
+            println!("   Solution: Create a new key with encryption capability or add a subkey for encryption");
         } else {
-            println!("List of secrets in vault:");
-            for (name, tags) in secrets {
-                println!("  {} tags: {}", name, tags);
-            }
+            println!("\n✅ Key is suitable for use with dark-matter");
         }
-        Ok(())
-    }
-
-    fn update_secret(name: &str, value: &str, tags: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        // Check if secret exists
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(id) FROM secrets WHERE name = ?1",
-            rusqlite::params![name],
-            |row| row.get(0),
-        )?;
+    })
+}
 
-        if count == 0 {
-            return Err(DmError::FileNotInStorage(name.to_string()));
+// Print hints for common GPG failures after the error itself
+fn print_error_hints(error: &DmError) {
+    match error {
+        DmError::GpgKeyNotFound(key_hash) => {
+            eprintln!("Try run: gpg --list-keys {}", key_hash);
         }
-
-        // Encrypt the new value
-        let key_hash = Self::get_gpg_key_hash(&conn)?;
-        let encrypted_value = Self::encrypt_content(value.as_bytes(), &key_hash)?;
-
-        // Update the secret
-        if !tags.is_empty() {
-            conn.execute(
-                "UPDATE secrets SET body = ?1, tags = ?2 WHERE name = ?3",
-                rusqlite::params![encrypted_value, tags, name],
-            )?;
-        } else {
-            conn.execute(
-                "UPDATE secrets SET body = ?1 WHERE name = ?2",
-                rusqlite::params![encrypted_value, name],
-            )?;
+        DmError::GpgError(e) if e.code() == 110 => {
+            // Using a generic error code for unusable pubkey
+            eprintln!("GPG key cannot be used for encryption.");
+            eprintln!("Possible reasons:");
+            eprintln!("1. Key expired");
+            eprintln!("2. Key revoked");
+            eprintln!("3. Key has no encryption subkey");
+            eprintln!("4. Insufficient trust level for key");
+            eprintln!();
+            eprintln!("Try running:");
+            eprintln!("  gpg --edit-key <key-hash> trust");
+            eprintln!("  (then select '5' for absolute trust)");
         }
-
-        println!("Secret '{}' successfully updated", name);
-        Ok(())
-    }
-
-    fn remove_secret(name: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        let rows_affected = conn.execute(
-            "DELETE FROM secrets WHERE name = ?1",
-            rusqlite::params![name],
-        )?;
-
-        if rows_affected == 0 {
-            println!("Secret '{}' not found in vault", name);
-        } else {
-            println!("Secret '{}' successfully removed from vault", name);
+        DmError::GpgError(e) if e.code() == 9 => {
+            // Generic "no secret key" error code
+            eprintln!("GPG key not found");
+            eprintln!("Make sure you have the corresponding private key");
         }
-        Ok(())
+        DmError::GpgError(e) if e.code() == 11 => {
+            // Generic "bad passphrase" error code
+            eprintln!("Invalid passphrase for private key");
+            eprintln!("Make sure gpg-agent is running and configured");
+        }
+        DmError::SignatureNotVerified(_) => {
+            eprintln!("The vault may have been modified by someone who is not an allowed signer.");
+            eprintln!(
+                "If other recipients write this vault, list their keys in DARK_MATTER_SIGNERS."
+            );
+        }
+        _ => {}
     }
+}
 
-    fn show_secret(name: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        // Get the encrypted secret
-        let encrypted_value: Vec<u8> = conn
-            .query_row(
-                "SELECT body FROM secrets WHERE name = ?1",
-                rusqlite::params![name],
-                |row| row.get(0),
-            )
-            .map_err(|_| DmError::SecretNotInStorage(name.to_string()))?;
+// A directory given as vault path means the default database file inside it
+fn vault_file(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(DB_NAME)
+    } else {
+        path.to_path_buf()
+    }
+}
 
-        // Decrypt the secret
-        let decrypted_value = Self::decrypt_content(&encrypted_value)?;
+fn init_vault(app: &App, key_hash: &str) -> Result<(), DmError> {
+    let path = app
+        .vault
+        .as_deref()
+        .map_or_else(|| PathBuf::from(DB_NAME), vault_file);
+    Vault::init(&path, key_hash)?;
+    app.emit(
+        json!({ "action": "initialized", "vault": path, "key_hash": key_hash }),
+        || println!("Vault initialized with GPG key: {}", key_hash),
+    )
+}
 
-        println!("{}", String::from_utf8_lossy(&decrypted_value));
-        Ok(())
+fn read_secret_value(args: SecretValueArgs) -> Result<Vec<u8>, DmError> {
+    if let Some(value) = args.value {
+        return Ok(value.into_bytes());
     }
 
-    // File management methods
-    fn add(filename: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-        let realpath = Self::get_absolute_path(filename)?;
+    if let Some(path) = args.from_file {
+        return Ok(fs::read(path)?);
+    }
 
-        // Check if file exists
-        if !Path::new(filename).exists() {
-            return Err(DmError::FileNotFound(filename.to_string()));
-        }
+    if args.stdin {
+        let mut value = Vec::new();
+        io::stdin().read_to_end(&mut value)?;
+        trim_line_ending(&mut value);
+        return Ok(value);
+    }
 
-        // Check if file already added
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM flist WHERE realpath = ?1",
-            rusqlite::params![&realpath],
-            |row| row.get(0),
-        )?;
+    let value = rpassword::prompt_password("Secret value: ")?;
+    let confirmation = rpassword::prompt_password("Repeat secret value: ")?;
+    if value != confirmation {
+        return Err(DmError::InvalidInput(
+            "Secret values do not match".to_string(),
+        ));
+    }
+    Ok(value.into_bytes())
+}
 
-        if count > 0 {
-            return Err(DmError::FileAlreadyExists(realpath));
+// Drop the line ending left by `echo` or a here-string
+fn trim_line_ending(value: &mut Vec<u8>) {
+    if value.ends_with(b"\n") {
+        value.pop();
+        if value.ends_with(b"\r") {
+            value.pop();
         }
-
-        // Read file content
-        let content = fs::read(filename)?;
-
-        // Get GPG key hash from configuration
-        let key_hash = Self::get_gpg_key_hash(&conn)?;
-
-        // Encrypt content
-        let encrypted_content = Self::encrypt_content(&content, &key_hash)?;
-
-        // Save to vault
-        conn.execute(
-            "INSERT INTO flist (realpath, body) VALUES (?1, ?2)",
-            rusqlite::params![&realpath, &encrypted_content],
-        )?;
-
-        println!("File '{}' successfully added to vault", filename);
-        Ok(())
     }
+}
 
-    fn list() -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-
-        let mut stmt = conn.prepare("SELECT realpath FROM flist ORDER BY realpath")?;
-        let file_iter = stmt.query_map([], |row| {
-            let path: String = row.get(0)?;
-            Ok(path)
-        })?;
+// Values that are not UTF-8 are base64 encoded in JSON output
+fn json_value(value: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(value) {
+        Ok(value) => json!({ "value": value, "encoding": "utf-8" }),
+        Err(_) => json!({
+            "value": base64::engine::general_purpose::STANDARD.encode(value),
+            "encoding": "base64",
+        }),
+    }
+}
 
-        let mut files = Vec::new();
-        for file in file_iter {
-            files.push(file?);
+fn handle_secrets_command(app: &App, action: SecretsCommands) -> Result<(), DmError> {
+    let mut vault = app.open_vault()?;
+    match action {
+        SecretsCommands::Add { name, value, tags } => {
+            let value = read_secret_value(value)?;
+            vault.add_secret(&name, &value, &tags)?;
+            app.emit(json!({ "action": "added", "secret": name }), || {
+                println!("Secret '{}' successfully added", name)
+            })
         }
-
-        if files.is_empty() {
-            println!("Vault is empty");
-        } else {
-            println!("List of files in vault:");
-            for file in files {
-                println!("  {}", file);
+        SecretsCommands::List { tags } => {
+            let secrets = vault.list_secrets(&tags)?;
+            app.emit(json!({ "secrets": &secrets }), || {
+                if secrets.is_empty() {
+                    println!("No secrets found in vault");
+                } else {
+                    println!("List of secrets in vault:");
+                    for secret in &secrets {
+                        println!("  {} tags: {}", secret.name, secret.tags.join(","));
+                    }
+                }
+            })
+        }
+        SecretsCommands::Update { name, value, tags } => {
+            let value = read_secret_value(value)?;
+            vault.update_secret(&name, &value, &tags)?;
+            app.emit(json!({ "action": "updated", "secret": name }), || {
+                println!("Secret '{}' successfully updated", name)
+            })
+        }
+        SecretsCommands::Remove { name } => {
+            let removed = vault.remove_secret(&name)?;
+            let action = if removed { "removed" } else { "not_found" };
+            app.emit(json!({ "action": action, "secret": name }), || {
+                if removed {
+                    println!("Secret '{}' successfully removed from vault", name);
+                } else {
+                    println!("Secret '{}' not found in vault", name);
+                }
+            })
+        }
+        SecretsCommands::Show { name, version } => {
+            let value = vault.show_secret(&name, version)?;
+            let mut json = json_value(&value);
+            json["secret"] = json!(name);
+            json["version"] = json!(version);
+            if app.format == OutputFormat::Json {
+                return app.emit(json, || {});
             }
+            // Binary values are written as is
+            match std::str::from_utf8(&value) {
+                Ok(value) => println!("{}", value),
+                Err(_) => io::stdout().write_all(&value)?,
+            }
+            Ok(())
         }
-
-        Ok(())
-    }
-
-    fn update(filename: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-        let realpath = Self::get_absolute_path(filename)?;
-
-        // Check if file exists on disk
-        if !Path::new(filename).exists() {
-            return Err(DmError::FileNotFound(filename.to_string()));
+        SecretsCommands::History { name } => {
+            let versions = vault.secret_history(&name)?;
+            app.emit(json!({ "secret": name, "versions": &versions }), || {
+                println!("History of secret '{}':", name);
+                for version in &versions {
+                    let marker = if version.current { " (current)" } else { "" };
+                    println!("  v{}  {}{}", version.version, version.created_at, marker);
+                }
+            })
         }
-
-        // Check if file exists in vault
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM flist WHERE realpath = ?1",
-            rusqlite::params![&realpath],
-            |row| row.get(0),
-        )?;
-
-        if count == 0 {
-            return Err(DmError::FileNotInStorage(realpath));
+        SecretsCommands::Rollback { name, version } => {
+            let new_version = vault.rollback_secret(&name, version)?;
+            let value = json!({
+                "action": "rolled_back",
+                "secret": name,
+                "version": version,
+                "new_version": new_version,
+            });
+            app.emit(value, || {
+                println!(
+                    "Secret '{}' rolled back to version {} (saved as version {})",
+                    name, version, new_version
+                )
+            })
         }
-
-        // Read new file content
-        let content = fs::read(filename)?;
-
-        // Get GPG key hash from configuration
-        let key_hash = Self::get_gpg_key_hash(&conn)?;
-
-        // Encrypt content
-        let encrypted_content = Self::encrypt_content(&content, &key_hash)?;
-
-        // Update record in vault
-        conn.execute(
-            "UPDATE flist SET body = ?1 WHERE realpath = ?2",
-            rusqlite::params![&encrypted_content, &realpath],
-        )?;
-
-        println!("File '{}' successfully updated in vault", filename);
-        Ok(())
-    }
-
-    fn remove(filename: &str) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-        let realpath = Self::get_absolute_path(filename)?;
-
-        let rows_affected = conn.execute(
-            "DELETE FROM flist WHERE realpath = ?1",
-            rusqlite::params![&realpath],
-        )?;
-
-        if rows_affected == 0 {
-            println!("File '{}' not found in vault", filename);
-        } else {
-            println!("File '{}' successfully removed from vault", filename);
+        SecretsCommands::Retention { count } => {
+            if let Some(count) = count {
+                vault.set_secret_history_limit(count)?;
+            }
+            let limit = vault.secret_history_limit()?;
+            app.emit(json!({ "retention": limit }), || match (count, limit) {
+                (Some(_), 0) => println!("All versions of secrets will be kept"),
+                (Some(_), limit) => println!("Keeping last {} versions of each secret", limit),
+                (None, 0) => println!("All versions of secrets are kept"),
+                (None, limit) => println!("Last {} versions of each secret are kept", limit),
+            })
         }
-
-        Ok(())
-    }
-
-    fn export(filename: &str, rel: bool, confirm: bool) -> Result<(), DmError> {
-        let conn = Self::open_database()?;
-        let realpath = Self::get_absolute_path(filename)?;
-
-        // Get the encrypted content from the vault
-        let encrypted_content: Vec<u8> = conn
-            .query_row(
-                "SELECT body FROM flist WHERE realpath = ?1",
-                rusqlite::params![&realpath],
-                |row| row.get(0),
-            )
-            .map_err(|_| DmError::FileNotInStorage(realpath))?;
-
-        // Decrypt the content
-        let decrypted_content = Self::decrypt_content(&encrypted_content)?;
-
-        // Get file name for saving
-        let mut output_filename = Path::new(filename).to_string_lossy();
-        if rel {
-            output_filename = Path::new(filename).file_name().unwrap().to_string_lossy();
-        }
-
-        if !confirm {
-            // Check if file exists
-            if Path::new(&*output_filename).exists() {
-                print!(
-                    "File '{}' already exists. Overwrite? (y/N): ",
-                    output_filename
-                );
-                io::stdout().flush()?;
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                if !input.trim().to_lowercase().starts_with('y') {
-                    println!("Export canceled");
-                    return Ok(());
+        SecretsCommands::Export {
+            format,
+            tags,
+            plaintext,
+        } => match format.into() {
+            ExportTarget::Env(format) => {
+                let vars = dark_matter::env_vars(vault.decrypt_secrets(&tags)?)?;
+                let output = dark_matter::format_env(&vars, format)?;
+                let value: serde_json::Map<_, _> = vars
+                    .iter()
+                    .map(|(var, value)| (var.clone(), json_value(value)))
+                    .collect();
+                app.emit(json!({ "variables": value }), || print!("{}", output))
+            }
+            ExportTarget::Records(format) => {
+                if !plaintext {
+                    return Err(DmError::InvalidInput(
+                        "This exports every secret in plain text, confirm with --plaintext"
+                            .to_string(),
+                    ));
                 }
+                let records = vault.secret_records(&tags)?;
+                let output = dark_matter::format_records(&records, format)?;
+                app.emit(json!({ "secrets": &records }), || print!("{}", output))
             }
+        },
+        SecretsCommands::Import {
+            file,
+            format,
+            dry_run,
+            on_conflict,
+        } => {
+            let input = if file == Path::new("-") {
+                let mut input = Vec::new();
+                io::stdin().read_to_end(&mut input)?;
+                input
+            } else {
+                fs::read(&file)?
+            };
+            let records = dark_matter::parse_records(&input, format.into())?;
+            let report = vault.import_secrets(records, on_conflict.into(), dry_run)?;
+            print_import_report(app, &report, dry_run)
         }
-
-        // Save decrypted content
-        fs::write(&*output_filename, decrypted_content)?;
-
-        println!("File '{}' exported", output_filename);
-        Ok(())
     }
+}
 
-    fn open_database() -> Result<Connection, DmError> {
-        if !Path::new(DB_NAME).exists() {
-            return Err(DmError::DatabaseNotFound);
+fn handle_key_command(app: &App, action: KeysCommands) -> Result<(), DmError> {
+    match action {
+        KeysCommands::Validate { key_hash } => diagnose_key(app, &key_hash),
+        KeysCommands::Rotate { new_key_hash } => {
+            let mut vault = app.open_vault()?;
+            let old_key_hash = vault.key_hash()?;
+            let count = vault.rotate_key(&new_key_hash)?;
+            let value = json!({
+                "action": if old_key_hash == new_key_hash { "unchanged" } else { "rotated" },
+                "old_key_hash": old_key_hash,
+                "new_key_hash": new_key_hash,
+                "reencrypted": count,
+            });
+            app.emit(value, || {
+                if old_key_hash == new_key_hash {
+                    println!("Key '{}' is already the vault key", new_key_hash);
+                } else {
+                    println!(
+                        "Vault key rotated from '{}' to '{}', {} entries re-encrypted",
+                        old_key_hash, new_key_hash, count
+                    );
+                }
+            })
         }
-        Ok(Connection::open(DB_NAME)?)
     }
+}
 
-    fn get_absolute_path(filename: &str) -> Result<String, DmError> {
-        let path = Path::new(filename);
-        let absolute_path = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            std::env::current_dir()?.join(path)
-        };
-
-        Ok(absolute_path.to_string_lossy().to_string())
+fn handle_recipients_command(app: &App, action: RecipientsCommands) -> Result<(), DmError> {
+    let mut vault = app.open_vault()?;
+    match action {
+        RecipientsCommands::Add { key_hash } => {
+            let count = vault.add_recipient(&key_hash)?;
+            let value = json!({ "action": "added", "recipient": key_hash, "reencrypted": count });
+            app.emit(value, || {
+                println!(
+                    "Recipient '{}' added, {} entries re-encrypted",
+                    key_hash, count
+                )
+            })
+        }
+        RecipientsCommands::Remove { key_hash } => {
+            let count = vault.remove_recipient(&key_hash)?;
+            let value = json!({ "action": "removed", "recipient": key_hash, "reencrypted": count });
+            app.emit(value, || {
+                println!(
+                    "Recipient '{}' removed, {} entries re-encrypted",
+                    key_hash, count
+                )
+            })
+        }
+        RecipientsCommands::List => {
+            let vault_key = vault.key_hash()?;
+            let recipients = vault.recipients()?;
+            let value: Vec<_> = recipients
+                .iter()
+                .map(|r| json!({ "key_hash": r, "vault_key": *r == vault_key }))
+                .collect();
+            app.emit(json!({ "recipients": value }), || {
+                println!("List of recipients of vault:");
+                for recipient in &recipients {
+                    if *recipient == vault_key {
+                        println!("  {} (vault key)", recipient);
+                    } else {
+                        println!("  {}", recipient);
+                    }
+                }
+            })
+        }
     }
+}
 
-    fn verify_gpg_key(key_hash: &str) -> Result<(), DmError> {
-        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
-
-        // Get the key by hash
-        match ctx.get_key(key_hash) {
-            Ok(key) => {
-                // Check if the key can be used for encryption
-                if key.can_encrypt() {
-                    println!("GPG key found and can be used for encryption");
-                    Ok(())
-                } else {
-                    eprintln!("Key cannot be used for encryption");
-                    eprintln!(
-                        "Please ensure the key is not expired and has encryption capabilities"
+fn handle_audit_command(app: &App, action: AuditCommands) -> Result<(), DmError> {
+    let vault = app.open_vault()?;
+    match action {
+        AuditCommands::List { limit } => {
+            let entries = vault.audit_log(limit)?;
+            app.emit(json!({ "entries": &entries }), || {
+                for entry in &entries {
+                    println!(
+                        "{}  {:<16} {}  by {} ({})",
+                        entry.created_at,
+                        entry.operation,
+                        entry.target,
+                        entry.os_user,
+                        entry.key_fingerprint.as_deref().unwrap_or("no key")
                     );
-                    Err(DmError::GpgKeyNotFound(format!(
-                        "{} (key cannot be used for encryption)",
-                        key_hash
-                    )))
                 }
-            }
-            Err(e) => {
-                eprintln!("GPG key not found: {}", e);
-                eprintln!("Try run: gpg --list-keys {}", key_hash);
-                Err(DmError::GpgKeyNotFound(key_hash.to_string()))
+            })
+        }
+        AuditCommands::Verify => {
+            let verification = vault.verify_audit_log()?;
+            app.emit(&verification, || {
+                println!("Checked {} entries", verification.checked);
+                for id in &verification.broken {
+                    println!("  entry {} does not match the hash chain", id);
+                }
+                if !verification.head_valid {
+                    println!("  the newest entry does not match the keyed head of the log");
+                }
+            })?;
+            if verification.is_valid() {
+                Ok(())
+            } else {
+                Err(DmError::AuditLogBroken(verification.broken))
             }
         }
     }
+}
 
-    fn get_gpg_key_hash(conn: &Connection) -> Result<String, DmError> {
-        let key_hash: String = conn.query_row(
-            "SELECT value FROM config WHERE key = ?1",
-            rusqlite::params![GPG_KEY_HASH_CONFIG],
-            |row| row.get(0),
-        )?;
-        Ok(key_hash)
+fn handle_vault_command(app: &App, action: VaultCommands) -> Result<(), DmError> {
+    // Restoring must also work when the vault is missing or broken
+    if let VaultCommands::Restore {
+        input,
+        merge,
+        confirm,
+        trust_settings,
+    } = action
+    {
+        return restore_vault(app, &input, merge, confirm, trust_settings);
     }
 
-    fn encrypt_content(content: &[u8], key_hash: &str) -> Result<Vec<u8>, DmError> {
-        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
-
-        // Set armor mode for better compatibility
-        ctx.set_armor(true);
-
-        // Get key
-        let key = ctx.get_key(key_hash)?;
-
-        // Check if key can encrypt
-        if !key.can_encrypt() {
-            return Err(DmError::GpgError(gpgme::Error::from_code(110))); // Generic unusable key error
+    let mut vault = app.open_vault()?;
+    match action {
+        VaultCommands::Check { headers_only } => {
+            let report = vault.check(!headers_only)?;
+            app.emit(&report, || print_check_report(&report))?;
+            match report.problems() {
+                0 => Ok(()),
+                problems => Err(DmError::CheckFailed(problems)),
+            }
         }
-
-        // Set trust mode (trust all keys)
-        ctx.set_offline(true);
-
-        let mut output = Vec::new();
-
-        // Encrypt with more detailed error handling
-        match ctx.encrypt(Some(&key), content, &mut output) {
-            Ok(_) => {
-                // println!(
-                //     "File encrypted successfully ({} bytes -> {} bytes)",
-                //     content.len(),
-                //     output.len()
-                // );
-                Ok(output)
+        VaultCommands::Settings {
+            armor,
+            compression,
+            signatures,
+        } => {
+            let current = vault.settings()?;
+            let settings = VaultSettings {
+                armor: armor.unwrap_or(current.armor),
+                compression: compression.map_or(current.compression, Compression::from),
+                signatures: signatures.map_or(current.signatures, SignaturePolicy::from),
+            };
+            if settings != current {
+                vault.set_settings(settings)?;
             }
-            Err(e) => {
-                eprintln!("Encrypt error: {}", e);
-                eprintln!("Error code: {}", e.code());
-
-                // Additional diagnostics
-                if e.code() == 110 {
-                    // Using a generic error code for unusable pubkey
-                    eprintln!("GPG key cannot be used for encryption.");
-                    eprintln!("Possible reasons:");
-                    eprintln!("1. Key expired");
-                    eprintln!("2. Key revoked");
-                    eprintln!("3. Key has no encryption subkey");
-                    eprintln!("4. Insufficient trust level for key");
-                    eprintln!("");
-                    eprintln!("Try running:");
-                    eprintln!("  gpg --edit-key {} trust", key_hash);
-                    eprintln!("  (then select '5' for absolute trust)");
+            app.emit(settings, || {
+                println!("Armor: {}", yes_no(settings.armor));
+                println!("Compression: {}", compression_name(settings.compression));
+                let signatures = match settings.signatures {
+                    SignaturePolicy::Require => "required",
+                    SignaturePolicy::Warn => "warn if not verified",
+                };
+                println!("Signatures: {}", signatures);
+                if app
+                    .signature_policy
+                    .is_some_and(|policy| policy != settings.signatures)
+                {
+                    println!("DARK_MATTER_SIGNATURES overrides this setting");
                 }
-
-                Err(DmError::GpgError(e))
-            }
+                if settings.armor != current.armor || settings.compression != current.compression {
+                    println!("Run 'dark-matter vault compact' to apply to stored content");
+                }
+            })
         }
+        VaultCommands::Compact => {
+            let size_before = fs::metadata(vault.path())?.len();
+            let count = vault.compact()?;
+            let size_after = fs::metadata(vault.path())?.len();
+            let value = json!({
+                "action": "compacted",
+                "rewritten": count,
+                "size_before": size_before,
+                "size_after": size_after,
+            });
+            app.emit(value, || {
+                println!(
+                    "Vault compacted, {} entries rewritten, {} -> {} bytes",
+                    count, size_before, size_after
+                )
+            })
+        }
+        VaultCommands::Backup { output, encrypt } => {
+            vault.backup(&output, encrypt)?;
+            app.emit(
+                json!({ "action": "backed_up", "file": output, "encrypted": encrypt }),
+                || println!("Vault backed up to '{}'", output.display()),
+            )
+        }
+        VaultCommands::Restore { .. } => unreachable!("handled above"),
     }
+}
 
-    fn decrypt_content(encrypted_content: &[u8]) -> Result<Vec<u8>, DmError> {
-        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
-
-        let mut output = Vec::new();
+fn restore_vault(
+    app: &App,
+    input: &Path,
+    merge: bool,
+    confirm: bool,
+    trust_settings: bool,
+) -> Result<(), DmError> {
+    let existing = app.open_vault().ok();
+    let backup = match &existing {
+        Some(vault) => vault.read_backup(input)?,
+        None => Vault::open_backup(input, &app.signers)?,
+    };
 
-        match ctx.decrypt(encrypted_content, &mut output) {
-            Ok(_) => {
-                // println!(
-                //     "File successfully decrypted ({} bytes -> {} bytes)",
-                //     encrypted_content.len(),
-                //     output.len()
-                // );
-                Ok(output)
+    if merge {
+        let mut vault = match existing {
+            Some(vault) => vault,
+            None => app.open_vault()?,
+        };
+        let merged = vault.merge_backup(&backup)?;
+        return app.emit(json!({ "action": "merged", "entries": &merged }), || {
+            for name in &merged.secrets {
+                println!("Secret '{}' added from backup", name);
             }
-            Err(e) => {
-                eprintln!("Decrypt error: {}", e);
-                eprintln!("Error code: {}", e.code());
-
-                if e.code() == 9 {
-                    // Generic "no secret key" error code
-                    eprintln!("GPG key not found");
-                    eprintln!("Make sure you have the corresponding private key");
-                } else if e.code() == 11 {
-                    // Generic "bad passphrase" error code
-                    eprintln!("Invalid passphrase for private key");
-                    eprintln!("Make sure gpg-agent is running and configured");
-                }
-
-                Err(DmError::GpgError(e))
+            for realpath in &merged.files {
+                println!("File '{}' added from backup", realpath);
             }
-        }
+            for name in &merged.skipped {
+                println!("'{}' already in vault, skipped", name);
+            }
+        });
     }
 
-    fn diagnose_key(key_hash: &str) -> Result<(), DmError> {
-        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+    if let Some(vault) = &existing {
+        vault.check_backup(&backup, trust_settings)?;
+    }
+    let path = match &existing {
+        Some(vault) => vault.path().to_path_buf(),
+        None => app.vault.as_deref().map_or_else(
+            || {
+                Vault::discover(std::env::current_dir()?)
+                    .or_else(|_| Ok::<_, DmError>(PathBuf::from(DB_NAME)))
+            },
+            |path| Ok(vault_file(path)),
+        )?,
+    };
+    if !confirm && !confirm_overwrite(&path)? {
+        return app.emit(json!({ "action": "canceled", "vault": path }), || {
+            println!("Restore canceled")
+        });
+    }
 
-        match ctx.get_key(key_hash) {
-            Ok(key) => {
-                println!("✅ GPG key found in keyring");
+    drop(existing);
+    Vault::restore_backup(&backup, &path)?;
+    let value = json!({
+        "action": "restored",
+        "vault": path,
+        "backup": input,
+        "encrypted": backup.encrypted,
+        "secrets": backup.secrets,
+        "files": backup.files,
+    });
+    app.emit(value, || {
+        println!(
+            "Vault restored from '{}', {} secrets and {} files",
+            input.display(),
+            backup.secrets,
+            backup.files
+        )
+    })
+}
 
-                // Check key capabilities
-                println!("\nKey capabilities:");
-                println!(
-                    "  - Encryption: {}",
-                    if key.can_encrypt() {
-                        "✅ Yes"
-                    } else {
-                        "❌ No"
-                    }
-                );
-                println!(
-                    "  - Signing: {}",
-                    if key.can_sign() { "✅ Yes" } else { "❌ No" }
-                );
-                println!(
-                    "  - Certification: {}",
-                    if key.can_certify() {
-                        "✅ Yes"
-                    } else {
-                        "❌ No"
-                    }
-                );
-                println!(
-                    "  - Authentication: {}",
-                    if key.can_authenticate() {
-                        "✅ Yes"
-                    } else {
-                        "❌ No"
-                    }
-                );
+fn handle_import_command(app: &App, action: ImportCommands) -> Result<(), DmError> {
+    let mut vault = app.open_vault()?;
+    match action {
+        ImportCommands::Pass {
+            dir,
+            dry_run,
+            on_conflict,
+        } => {
+            let dir = match dir {
+                Some(dir) => dir,
+                None => std::env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".password-store"))
+                    .ok_or_else(|| {
+                        DmError::InvalidInput("Password store directory not given".to_string())
+                    })?,
+            };
+            let report = vault.import_pass(&dir, on_conflict.into(), dry_run)?;
+            print_import_report(app, &report, dry_run)
+        }
+    }
+}
 
-                // Display key details
-                println!("\nDetails:");
-                println!("  - ID: {}", key.id().unwrap_or("Unknown"));
-                println!(
-                    "  - Fingerprint: {}",
-                    key.fingerprint().unwrap_or("Unknown")
-                );
+fn print_import_report(
+    app: &App,
+    report: &dark_matter::ImportReport,
+    dry_run: bool,
+) -> Result<(), DmError> {
+    let value = json!({ "action": "imported", "dry_run": dry_run, "secrets": report });
+    app.emit(value, || {
+        let (added, overwritten) = if dry_run {
+            ("would be added", "would be overwritten")
+        } else {
+            ("added", "overwritten")
+        };
+        for name in &report.added {
+            println!("Secret '{}' {}", name, added);
+        }
+        for name in &report.overwritten {
+            println!("Secret '{}' {}", name, overwritten);
+        }
+        for name in &report.skipped {
+            println!("Secret '{}' already in vault, skipped", name);
+        }
+        println!(
+            "{} added, {} overwritten, {} skipped",
+            report.added.len(),
+            report.overwritten.len(),
+            report.skipped.len()
+        );
+        if dry_run {
+            println!("Dry run, nothing was imported");
+        }
+    })
+}
 
-                // Collect subkeys - direct collection without Result handling
-                let subkeys: Vec<_> = key.subkeys().collect();
+fn print_check_report(report: &dark_matter::CheckReport) {
+    for problem in &report.integrity {
+        println!("❌ database: {}", problem);
+    }
+    for entry in &report.entries {
+        let name = match entry.version {
+            Some(version) => format!("{} {} v{}", entry.kind, entry.name, version),
+            None => format!("{} {}", entry.kind, entry.name),
+        };
+        match &entry.error {
+            None => println!("✅ {} (encrypted to {})", name, entry.key_ids.join(", ")),
+            Some(error) => println!("❌ {}: {}", name, error),
+        }
+    }
+    println!(
+        "Checked {} entries, {} problems",
+        report.entries.len(),
+        report.problems()
+    );
+}
 
-                println!("Subkeys ({}):", subkeys.len());
-                for (i, subkey) in subkeys.iter().enumerate() {
-                    println!("  Subkey #{}", i + 1);
-                    println!("    - ID: {}", subkey.id().unwrap_or("Unknown"));
-                    println!(
-                        "    - Can encrypt: {}",
-                        if subkey.can_encrypt() {
-                            "✅ Yes"
-                        } else {
-                            "❌ No"
-                        }
-                    );
-                }
+fn compression_name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::None => "none",
+        Compression::Zstd => "zstd",
+    }
+}
 
-                // Collect user IDs - direct collection without Result handling
-                let uids: Vec<_> = key.user_ids().collect();
+fn render_template(app: &App, template: &Path, output: &Path, store: bool) -> Result<(), DmError> {
+    let mut vault = app.open_vault()?;
+    let template = fs::read_to_string(template)?;
+    let rendered = vault.render_template(&template)?;
 
-                println!("\nUser IDs ({}):", uids.len());
-                for (i, uid) in uids.iter().enumerate() {
-                    println!("  ID #{}", i + 1);
-                    println!("    - Name: {}", uid.name().unwrap_or("Unknown"));
-                    println!("    - Email: {}", uid.email().unwrap_or("Unknown"));
-                }
+    // Only the owner may read the rendered secrets, also when replacing a file
+    dark_matter::write_atomic(output, None, false, |file| Ok(file.write_all(&rendered)?))?;
 
-                // Test encryption capability with a small message
-                if key.can_encrypt() {
-                    println!("\nEncryption testing:");
-                    let test_data = b"Test encryption capability";
-                    match Self::encrypt_content(test_data, key_hash) {
-                        Ok(_) => println!("  ✅ Encryption successful"),
-                        Err(e) => println!("  ❌ Encryption failed: {}", e),
-                    }
-                } else {
-                    println!("\nEncryption testing: ❌ Skipped (key does not support encryption)");
-                }
+    let stored = if store {
+        let realpath = match vault.add_file(output) {
+            Err(DmError::FileAlreadyExists(_)) => vault.update_file(output)?,
+            result => result?,
+        };
+        Some(realpath)
+    } else {
+        None
+    };
 
-                // Additional diagnostics and recommendations
-                if !key.can_encrypt() {
-                    println!("\n❌ Problem: Key cannot be used for encryption");
-                    println!("   Solution: Create a new key with encryption capability or add a subkey for encryption");
-                } else {
-                    println!("\n✅ Key is suitable for use with dark-matter");
-                }
+    let value = json!({ "action": "rendered", "file": output, "stored": stored });
+    app.emit(value, || {
+        println!("Template rendered to '{}'", output.display());
+        if let Some(realpath) = &stored {
+            println!("File '{}' stored in vault", realpath);
+        }
+    })
+}
 
-                Ok(())
-            }
-            Err(e) => {
-                println!("❌ GPG key not found: {}", e);
-                println!("\nDiagnosis:");
-                println!("1. Check the hash: {}", key_hash);
-                println!("2. Check available keys:");
-                println!("   $ gpg --list-keys");
-                println!("3. Maybe you need to import the key:");
-                println!("   $ gpg --import path/to/key.asc");
+fn run_command(app: &App, tags: &str, command: &[String]) -> Result<(), DmError> {
+    let vault = app.open_vault()?;
+
+    // Secret names map to variable names, e.g. db-password becomes DB_PASSWORD
+    let env = dark_matter::env_vars(vault.decrypt_secrets(tags)?)?;
+    drop(vault);
+
+    // exec replaces this process, so the exit code of the command is ours
+    let error = Command::new(&command[0])
+        .args(&command[1..])
+        .envs(
+            env.into_iter()
+                .map(|(var, value)| (var, OsString::from_vec(value))),
+        )
+        .exec();
+    Err(DmError::IoError(io::Error::new(
+        error.kind(),
+        format!("failed to run '{}': {}", command[0], error),
+    )))
+}
 
-                Err(DmError::GpgKeyNotFound(key_hash.to_string()))
-            }
-        }
+fn export_file(
+    app: &App,
+    vault: &Vault,
+    filename: &str,
+    version: Option<i64>,
+    rel: bool,
+    confirm: bool,
+    no_owner: bool,
+) -> Result<(), DmError> {
+    // Get file name for saving
+    let mut output_filename = Path::new(filename).to_string_lossy();
+    if rel {
+        output_filename = Path::new(filename).file_name().unwrap().to_string_lossy();
+    }
+
+    if !confirm && !confirm_overwrite(Path::new(&*output_filename))? {
+        return app.emit(
+            json!({ "action": "canceled", "file": output_filename }),
+            || println!("Export canceled"),
+        );
     }
+
+    // Save decrypted content
+    write_export(
+        vault,
+        filename,
+        version,
+        Path::new(&*output_filename),
+        no_owner,
+    )?;
+
+    app.emit(
+        json!({ "action": "exported", "file": output_filename }),
+        || println!("File '{}' exported", output_filename),
+    )
 }
 
-fn handle_secrets_command(action: SecretsCommands) -> Result<(), DmError> {
-    match action {
-        SecretsCommands::Add { name, value, tags } => {
-            // Here you would implement the logic to add a secret
-            //println!("Adding secret '{}' with tags '{}'", name, tags);
-            DataManager::add_secret(&name, &value, &tags).map_err(|e| {
-                eprintln!("Error adding secret: {}", e);
-                e
-            })?;
-            Ok(())
-        }
-        SecretsCommands::List { tags } => {
-            // Here you would implement the logic to list secrets
-            //println!("Listing all secrets");
-            DataManager::list_secrets(&tags).map_err(|e| {
-                eprintln!("Error listing secrets: {}", e);
-                e
-            })?;
-            Ok(())
+fn export_dir(
+    app: &App,
+    vault: &Vault,
+    prefix: &str,
+    rel: bool,
+    confirm: bool,
+    no_owner: bool,
+) -> Result<(), DmError> {
+    let files = vault.list_files_under(prefix)?;
+    if files.is_empty() {
+        return Err(DmError::FileNotInStorage(dark_matter::absolute_path(
+            prefix,
+        )?));
+    }
+
+    // Relative export keeps the tree below the parent of the prefix, so ~/.ssh becomes ./.ssh
+    let prefix = PathBuf::from(dark_matter::absolute_path(prefix)?);
+    let base = prefix.parent().unwrap_or(Path::new("/"));
+
+    let mut exported = Vec::new();
+    let mut canceled = Vec::new();
+    for realpath in files {
+        let output = if rel {
+            Path::new(&realpath)
+                .strip_prefix(base)
+                .unwrap()
+                .to_path_buf()
+        } else {
+            PathBuf::from(&realpath)
+        };
+
+        if !confirm && !confirm_overwrite(&output)? {
+            canceled.push(output);
+            continue;
         }
-        SecretsCommands::Update { name, value, tags } => {
-            // Here you would implement the logic to update a secret
-            //println!("Updating secret '{}' with tags '{}'", name, tags);
-            DataManager::update_secret(&name, &value, &tags).map_err(|e| {
-                eprintln!("Error updating secret: {}", e);
-                e
-            })?;
-            Ok(())
+
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
         }
-        SecretsCommands::Remove { name } => {
-            // Here you would implement the logic to remove a secret
-            //println!("Removing secret '{}'", name);
-            DataManager::remove_secret(&name).map_err(|e| {
-                eprintln!("Error removing secret: {}", e);
-                e
-            })?;
-            Ok(())
+        write_export(vault, &realpath, None, &output, no_owner)?;
+        exported.push(output);
+    }
+
+    let value = json!({ "action": "exported", "files": &exported, "canceled": &canceled });
+    app.emit(value, || {
+        for file in &exported {
+            println!("File '{}' exported", file.display());
         }
-        SecretsCommands::Show { name } => {
-            // Here you would implement the logic to show a secret
-            //println!("Showing secret '{}'", name);
-            DataManager::show_secret(&name).map_err(|e| {
-                eprintln!("Error showing secret: {}", e);
-                e
-            })?;
-            Ok(())
+        for file in &canceled {
+            println!("File '{}' not overwritten", file.display());
         }
-    }
+    })
 }
 
-fn handle_key_command(action: KeysCommands) -> Result<(), DmError> {
-    match action {
-        KeysCommands::Validate { key_hash } => DataManager::diagnose_key(&key_hash),
+fn write_export(
+    vault: &Vault,
+    filename: &str,
+    version: Option<i64>,
+    output: &Path,
+    no_owner: bool,
+) -> Result<(), DmError> {
+    // Files stored before metadata was recorded are written with 0600
+    let metadata = vault.file_metadata(filename, version)?;
+    dark_matter::write_atomic(output, metadata.as_ref(), !no_owner, |file| {
+        vault.export_file_to(filename, version, file)
+    })
+}
+
+fn confirm_overwrite(path: &Path) -> Result<bool, DmError> {
+    if !path.exists() {
+        return Ok(true);
     }
+    eprint!(
+        "File '{}' already exists. Overwrite? (y/N): ",
+        path.display()
+    );
+    io::stderr().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_lowercase().starts_with('y'))
 }
 
-fn handle_file_command(action: FileCommands) -> Result<(), DmError> {
+fn handle_file_command(app: &App, action: FileCommands) -> Result<(), DmError> {
+    let mut vault = app.open_vault()?;
     match action {
-        FileCommands::Add { filename } => DataManager::add(&filename),
-        FileCommands::List => DataManager::list(),
-        FileCommands::Update { filename } => DataManager::update(&filename),
-        FileCommands::Remove { filename } => DataManager::remove(&filename),
+        FileCommands::Add {
+            filename,
+            recursive: true,
+        } => {
+            let files = vault.add_dir(&filename)?;
+            app.emit(json!({ "action": "added", "files": &files }), || {
+                for file in &files.added {
+                    println!("File '{}' successfully added to vault", file);
+                }
+                for file in &files.skipped {
+                    println!("File '{}' already in vault, skipped", file);
+                }
+            })
+        }
+        FileCommands::Add { filename, .. } => {
+            let realpath = vault.add_file(&filename)?;
+            app.emit(json!({ "action": "added", "file": realpath }), || {
+                println!("File '{}' successfully added to vault", filename)
+            })
+        }
+        FileCommands::List => {
+            let files = vault.list_files()?;
+            app.emit(json!({ "files": &files }), || {
+                if files.is_empty() {
+                    println!("Vault is empty");
+                } else {
+                    println!("List of files in vault:");
+                    for file in &files {
+                        println!("  {}", file);
+                    }
+                }
+            })
+        }
+        FileCommands::Update { filename } => {
+            let realpath = vault.update_file(&filename)?;
+            app.emit(json!({ "action": "updated", "file": realpath }), || {
+                println!("File '{}' successfully updated in vault", filename)
+            })
+        }
+        FileCommands::Remove { filename } => {
+            let removed = vault.remove_file(&filename)?;
+            let action = if removed { "removed" } else { "not_found" };
+            let realpath = dark_matter::absolute_path(&filename)?;
+            app.emit(json!({ "action": action, "file": realpath }), || {
+                if removed {
+                    println!("File '{}' successfully removed from vault", filename);
+                } else {
+                    println!("File '{}' not found in vault", filename);
+                }
+            })
+        }
         FileCommands::Export {
             filename,
             relative,
+            recursive: true,
             confirm,
-        } => DataManager::export(&filename, relative, confirm),
+            no_owner,
+            ..
+        } => export_dir(app, &vault, &filename, relative, confirm, no_owner),
+        FileCommands::Export {
+            filename,
+            version,
+            relative,
+            confirm,
+            no_owner,
+            ..
+        } => export_file(app, &vault, &filename, version, relative, confirm, no_owner),
+        FileCommands::History { filename } => {
+            let versions = vault.file_history(&filename)?;
+            let realpath = dark_matter::absolute_path(&filename)?;
+            app.emit(json!({ "file": realpath, "versions": &versions }), || {
+                println!("History of file '{}':", realpath);
+                for version in &versions {
+                    let size = match version.size {
+                        Some(size) => format!("{} bytes", size),
+                        None => "unknown size".to_string(),
+                    };
+                    let marker = if version.current { " (current)" } else { "" };
+                    println!(
+                        "  v{}  {}  {}{}",
+                        version.version, version.created_at, size, marker
+                    );
+                }
+            })
+        }
+        FileCommands::Diff { filename, version } => {
+            let diff = vault.diff_file(&filename, version)?;
+            let realpath = dark_matter::absolute_path(&filename)?;
+            let value = json!({ "file": realpath, "changed": diff.changed(), "diff": &diff });
+            app.emit(value, || match &diff {
+                _ if !diff.changed() => println!("File '{}' is unchanged", realpath),
+                FileDiff::Text { unified } => print!("{}", unified),
+                FileDiff::Binary { vault, disk } => {
+                    println!("Binary file '{}' differs:", realpath);
+                    println!("  vault: {} bytes, hash {}", vault.size, vault.content_hash);
+                    println!("  disk:  {} bytes, hash {}", disk.size, disk.content_hash);
+                }
+            })
+        }
+        FileCommands::Status { paths } => {
+            let statuses = vault.file_status(&paths)?;
+            app.emit(json!({ "files": &statuses }), || {
+                if statuses.is_empty() {
+                    println!("No files to compare");
+                }
+                for status in &statuses {
+                    let state = match status.state {
+                        FileState::Unchanged => "unchanged",
+                        FileState::Modified => "modified",
+                        FileState::Missing => "missing",
+                        FileState::New => "new",
+                    };
+                    println!("  {:<10} {}", state, status.path);
+                }
+            })
+        }
+        FileCommands::Rollback { filename, version } => {
+            let new_version = vault.rollback_file(&filename, version)?;
+            let value = json!({
+                "action": "rolled_back",
+                "file": dark_matter::absolute_path(&filename)?,
+                "version": version,
+                "new_version": new_version,
+            });
+            app.emit(value, || {
+                println!(
+                    "File '{}' rolled back to version {} (saved as version {})",
+                    filename, version, new_version
+                )
+            })
+        }
     }
 }
 
 fn main() {
     let cli = Cli::parse();
+    let app = App {
+        vault: cli.vault,
+        signers: cli.signers,
+        signature_policy: cli.signature_policy.map(SignaturePolicy::from),
+        format: cli.format,
+    };
     let result = match cli.command {
-        Commands::Init { key_hash } => DataManager::init(&key_hash),
-        Commands::File { action } => handle_file_command(action),
-        Commands::Keys { action } => handle_key_command(action),
-        Commands::Secret { action } => handle_secrets_command(action),
+        Commands::Init { key_hash } => init_vault(&app, &key_hash),
+        Commands::File { action } => handle_file_command(&app, action),
+        Commands::Keys { action } => handle_key_command(&app, action),
+        Commands::Secret { action } => handle_secrets_command(&app, action),
+        Commands::Recipients { action } => handle_recipients_command(&app, action),
+        Commands::Audit { action } => handle_audit_command(&app, action),
+        Commands::Vault { action } => handle_vault_command(&app, action),
+        Commands::Import { action } => handle_import_command(&app, action),
+        Commands::Render {
+            template,
+            output,
+            store,
+        } => render_template(&app, &template, &output, store),
+        Commands::Run { tags, command } => run_command(&app, &tags, &command),
     };
     if let Err(error) = result {
-        eprintln!("{}", error);
+        app.report_error(&error);
         std::process::exit(1);
     }
 }
@@ -883,18 +1610,67 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::env;
-    use tempfile::TempDir;
+    use clap::CommandFactory;
 
     #[test]
-    fn test_get_absolute_path() {
-        let temp_dir = TempDir::new().unwrap();
-        env::set_current_dir(&temp_dir).unwrap();
+    fn test_trim_line_ending() {
+        for (input, expected) in [
+            (&b"secret\n"[..], &b"secret"[..]),
+            (b"secret\r\n", b"secret"),
+            (b"secret\n\n", b"secret\n"),
+            (b"secret", b"secret"),
+            (b"\r", b"\r"),
+            (b"", b""),
+        ] {
+            let mut value = input.to_vec();
+            trim_line_ending(&mut value);
+            assert_eq!(value, expected);
+        }
+    }
 
-        let relative_path = "test.txt";
-        let absolute_path = DataManager::get_absolute_path(relative_path).unwrap();
+    #[test]
+    fn test_format_args() {
+        Cli::command().debug_assert();
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(std::iter::once("dark-matter").chain(args.iter().copied())).unwrap()
+        };
 
-        assert!(absolute_path.contains("test.txt"));
-        assert!(Path::new(&absolute_path).is_absolute());
+        for args in [
+            &["--format", "json", "secret", "list"][..],
+            &["secret", "list", "--format", "json"],
+        ] {
+            assert!(parse(args).format == OutputFormat::Json);
+        }
+
+        // Export and import take the file format as --format
+        let cli = parse(&[
+            "secret",
+            "export",
+            "--format",
+            "csv",
+            "--output-format",
+            "json",
+        ]);
+        assert!(cli.format == OutputFormat::Json);
+        assert!(matches!(
+            cli.command,
+            Commands::Secret {
+                action: SecretsCommands::Export {
+                    format: ExportFormat::Csv,
+                    ..
+                }
+            }
+        ));
+        let cli = parse(&["secret", "import", "-", "--format", "json"]);
+        assert!(cli.format == OutputFormat::Text);
+        assert!(matches!(
+            cli.command,
+            Commands::Secret {
+                action: SecretsCommands::Import {
+                    format: RecordFormatArg::Json,
+                    ..
+                }
+            }
+        ));
     }
 }
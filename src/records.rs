@@ -0,0 +1,165 @@
+use crate::error::DmError;
+use crate::vault::Vault;
+use serde::{Deserialize, Serialize};
+
+/// Formats of [`parse_records`] and [`format_records`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// One row per secret with `name`, `value`, `tags`, `version` and `updated_at`
+    /// columns. Tags are comma-separated.
+    Csv,
+    /// An array of objects
+    Json,
+    /// A sequence of mappings
+    Yaml,
+}
+
+/// A secret in plain text, as exported by [`Vault::secret_records`] and imported
+/// by [`Vault::import_secrets`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretRecord {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Current version in the vault, informational and ignored on import
+    #[serde(default)]
+    pub version: Option<i64>,
+    /// When the current version was stored, informational and ignored on import
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+/// [`SecretRecord`] as a CSV row, which cannot hold lists.
+#[derive(Serialize, Deserialize)]
+struct CsvRecord {
+    name: String,
+    value: String,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    version: Option<i64>,
+    #[serde(default)]
+    updated_at: Option<String>,
+}
+
+impl Vault {
+    /// Decrypts all secrets with any of the comma-separated `tags` into records,
+    /// sorted by name. Values must be valid UTF-8.
+    pub fn secret_records(&self, tags: &str) -> Result<Vec<SecretRecord>, DmError> {
+        let mut records = Vec::new();
+        for (secret, value) in self.decrypt_secrets(tags)? {
+            let value = String::from_utf8(value).map_err(|_| {
+                DmError::InvalidInput(format!(
+                    "Value of secret '{}' is not valid UTF-8",
+                    secret.name
+                ))
+            })?;
+            let current = self.secret_history(&secret.name)?.pop();
+            records.push(SecretRecord {
+                version: current.as_ref().map(|v| v.version),
+                updated_at: current.map(|v| v.created_at),
+                name: secret.name,
+                value,
+                tags: secret.tags,
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// Reads secret records written by [`format_records`] or by hand. Only `name` and
+/// `value` are required.
+pub fn parse_records(input: &[u8], format: RecordFormat) -> Result<Vec<SecretRecord>, DmError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        DmError::InvalidInput(format!("Invalid {:?} input: {}", format, e))
+    };
+    match format {
+        RecordFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let mut records = Vec::new();
+            for row in reader.deserialize() {
+                let row: CsvRecord = row.map_err(|e| invalid(&e))?;
+                records.push(SecretRecord {
+                    name: row.name,
+                    value: row.value,
+                    tags: split_tags(&row.tags),
+                    version: row.version,
+                    updated_at: row.updated_at,
+                });
+            }
+            Ok(records)
+        }
+        RecordFormat::Json => serde_json::from_slice(input).map_err(|e| invalid(&e)),
+        RecordFormat::Yaml => serde_norway::from_slice(input).map_err(|e| invalid(&e)),
+    }
+}
+
+/// Writes secret records in plain text.
+pub fn format_records(records: &[SecretRecord], format: RecordFormat) -> Result<String, DmError> {
+    let invalid = |e: &dyn std::fmt::Display| DmError::InvalidInput(e.to_string());
+    match format {
+        RecordFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for record in records {
+                writer
+                    .serialize(CsvRecord {
+                        name: record.name.clone(),
+                        value: record.value.clone(),
+                        tags: record.tags.join(","),
+                        version: record.version,
+                        updated_at: record.updated_at.clone(),
+                    })
+                    .map_err(|e| invalid(&e))?;
+            }
+            let output = writer.into_inner().map_err(|e| invalid(&e))?;
+            String::from_utf8(output).map_err(|e| invalid(&e))
+        }
+        RecordFormat::Json => {
+            let mut output = serde_json::to_string_pretty(records).map_err(|e| invalid(&e))?;
+            output.push('\n');
+            Ok(output)
+        }
+        RecordFormat::Yaml => serde_norway::to_string(records).map_err(|e| invalid(&e)),
+    }
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_round_trip() {
+        let records = vec![
+            SecretRecord {
+                name: "db-password".to_string(),
+                value: "p@ss, \"word\"\nsecond line".to_string(),
+                tags: vec!["prod".to_string(), "db".to_string()],
+                version: Some(3),
+                updated_at: Some("2024-01-01 00:00:00".to_string()),
+            },
+            SecretRecord {
+                name: "token".to_string(),
+                value: String::new(),
+                ..SecretRecord::default()
+            },
+        ];
+        for format in [RecordFormat::Csv, RecordFormat::Json, RecordFormat::Yaml] {
+            let output = format_records(&records, format).unwrap();
+            assert_eq!(parse_records(output.as_bytes(), format).unwrap(), records);
+        }
+
+        let csv = b"name,value,url\napi,secret,https://example.com\n";
+        let parsed = parse_records(csv, RecordFormat::Csv).unwrap();
+        assert_eq!(parsed[0].name, "api");
+        assert!(parsed[0].tags.is_empty());
+        assert!(parse_records(b"- name: api\n", RecordFormat::Yaml).is_err());
+    }
+}
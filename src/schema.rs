@@ -0,0 +1,336 @@
+use crate::error::DmError;
+use crate::{GPG_KEY_HASH_CONFIG, SCHEMA_VERSION_CONFIG, SIGNATURES_CONFIG};
+use rusqlite::{Connection, OptionalExtension};
+
+type Migration = fn(&Connection) -> Result<(), DmError>;
+
+// Schema version of a vault is the number of applied migrations,
+// so new migrations must only ever be appended to this list.
+const MIGRATIONS: [Migration; 11] = [
+    migrate_base_tables,
+    migrate_file_versions,
+    migrate_secret_versions,
+    migrate_recipients,
+    migrate_content_hash,
+    migrate_file_metadata,
+    migrate_file_chunks,
+    migrate_compression,
+    migrate_signatures,
+    migrate_audit_log,
+    migrate_audit_head,
+];
+
+/// Newest vault schema version this build understands.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+pub(crate) fn migrate(conn: &mut Connection) -> Result<(), DmError> {
+    let current = schema_version(conn)?;
+    if current > SCHEMA_VERSION {
+        return Err(DmError::UnsupportedSchemaVersion(current));
+    }
+
+    // Each migration is applied in its own transaction together with the version bump
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(current as usize) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO config (key, value) VALUES (?1, ?2)",
+            rusqlite::params![SCHEMA_VERSION_CONFIG, version.to_string()],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub(crate) fn schema_version(conn: &Connection) -> Result<i64, DmError> {
+    if !table_exists(conn, "config")? {
+        return Ok(0);
+    }
+
+    // Vaults created before schema versioning have no version recorded
+    let version: Option<String> = conn
+        .query_row(
+            "SELECT value FROM config WHERE key = ?1",
+            rusqlite::params![SCHEMA_VERSION_CONFIG],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(version.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+pub(crate) fn table_exists(conn: &Connection, name: &str) -> Result<bool, DmError> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        rusqlite::params![name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn migrate_base_tables(conn: &Connection) -> Result<(), DmError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS config (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS secrets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL UNIQUE,
+            body BLOB NOT NULL,
+            tags TEXT DEFAULT ''
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS flist (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            realpath TEXT NOT NULL UNIQUE,
+            body BLOB NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_file_versions(conn: &Connection) -> Result<(), DmError> {
+    if table_exists(conn, "file_versions")? {
+        return Ok(());
+    }
+
+    conn.execute(
+        "CREATE TABLE file_versions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            realpath TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            body BLOB NOT NULL,
+            size INTEGER,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            UNIQUE (realpath, version)
+        )",
+        [],
+    )?;
+
+    // Vaults created before versioning keep their current bodies as version 1
+    conn.execute(
+        "INSERT INTO file_versions (realpath, version, body, size)
+         SELECT realpath, 1, body, NULL FROM flist",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_secret_versions(conn: &Connection) -> Result<(), DmError> {
+    if table_exists(conn, "secret_versions")? {
+        return Ok(());
+    }
+
+    conn.execute(
+        "CREATE TABLE secret_versions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            body BLOB NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (datetime('now')),
+            UNIQUE (name, version)
+        )",
+        [],
+    )?;
+
+    // Vaults created before versioning keep their current values as version 1
+    conn.execute(
+        "INSERT INTO secret_versions (name, version, body)
+         SELECT name, 1, body FROM secrets",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_recipients(conn: &Connection) -> Result<(), DmError> {
+    if table_exists(conn, "recipients")? {
+        return Ok(());
+    }
+
+    conn.execute(
+        "CREATE TABLE recipients (
+            key_hash TEXT PRIMARY KEY,
+            added_at TEXT NOT NULL DEFAULT (datetime('now'))
+        )",
+        [],
+    )?;
+
+    // The vault key is always the first recipient
+    conn.execute(
+        "INSERT INTO recipients (key_hash)
+         SELECT value FROM config WHERE key = ?1",
+        rusqlite::params![GPG_KEY_HASH_CONFIG],
+    )?;
+    Ok(())
+}
+
+fn migrate_content_hash(conn: &Connection) -> Result<(), DmError> {
+    // Hashes of existing files stay NULL until they are updated,
+    // computing them here would need the GPG key
+    conn.execute("ALTER TABLE flist ADD COLUMN content_hash TEXT", [])?;
+    conn.execute("ALTER TABLE file_versions ADD COLUMN content_hash TEXT", [])?;
+    Ok(())
+}
+
+fn migrate_file_metadata(conn: &Connection) -> Result<(), DmError> {
+    for table in ["flist", "file_versions"] {
+        for column in ["mode", "uid", "gid", "mtime"] {
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} INTEGER", table, column),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn migrate_file_chunks(conn: &Connection) -> Result<(), DmError> {
+    // File content moves out of single blobs, which SQLite limits to 1 GB
+    // and which have to be held in memory as a whole
+    conn.execute(
+        "CREATE TABLE file_chunks (
+            version_id INTEGER NOT NULL,
+            seq INTEGER NOT NULL,
+            data BLOB NOT NULL,
+            PRIMARY KEY (version_id, seq)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "INSERT INTO file_chunks (version_id, seq, data)
+         SELECT id, 0, body FROM file_versions",
+        [],
+    )?;
+
+    // The current content of a file is its latest version, flist keeps no copy
+    conn.execute("ALTER TABLE file_versions DROP COLUMN body", [])?;
+    conn.execute("ALTER TABLE flist DROP COLUMN body", [])?;
+    Ok(())
+}
+
+fn migrate_compression(conn: &Connection) -> Result<(), DmError> {
+    // Existing rows are uncompressed, which NULL stands for
+    for table in ["secrets", "secret_versions", "file_versions"] {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN compression TEXT", table),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn migrate_signatures(conn: &Connection) -> Result<(), DmError> {
+    // Content stored so far is unsigned, refusing it would lock users out
+    // until they run `vault compact`
+    conn.execute(
+        "INSERT INTO config (key, value)
+         SELECT ?1, 'warn'
+         WHERE EXISTS (SELECT 1 FROM secrets) OR EXISTS (SELECT 1 FROM file_versions)",
+        rusqlite::params![SIGNATURES_CONFIG],
+    )?;
+    Ok(())
+}
+
+fn migrate_audit_log(conn: &Connection) -> Result<(), DmError> {
+    conn.execute(
+        "CREATE TABLE audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at TEXT NOT NULL,
+            operation TEXT NOT NULL,
+            target TEXT NOT NULL,
+            os_user TEXT NOT NULL,
+            key_fingerprint TEXT,
+            prev_hash TEXT NOT NULL,
+            hash TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    // Only stops accidental changes, tampering is detected by the hash chain
+    conn.execute(
+        "CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+         BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+         BEGIN SELECT RAISE(ABORT, 'audit log is append-only'); END",
+        [],
+    )?;
+    Ok(())
+}
+
+fn migrate_audit_head(conn: &Connection) -> Result<(), DmError> {
+    // Keyed record of the newest entry, written with the first entry after the upgrade
+    conn.execute(
+        "CREATE TABLE audit_head (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            entries INTEGER NOT NULL,
+            legacy_entries INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            mac TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Vault;
+
+    #[test]
+    fn test_migrate_legacy_vault() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_base_tables(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO config (key, value) VALUES (?1, 'ABCDEF')",
+            rusqlite::params![GPG_KEY_HASH_CONFIG],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO secrets (name, body) VALUES ('api_key', x'00')",
+            [],
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(Vault::get_recipients(&conn).unwrap(), ["ABCDEF"]);
+        assert!(Vault::get_secret_version(&conn, "api_key", 1).is_ok());
+        assert_eq!(
+            Vault::get_settings(&conn).unwrap().signatures,
+            crate::SignaturePolicy::Warn
+        );
+
+        // Running migrations again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(Vault::get_recipients(&conn).unwrap(), ["ABCDEF"]);
+    }
+
+    #[test]
+    fn test_migrate_refuses_newer_vault() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "UPDATE config SET value = ?1 WHERE key = ?2",
+            rusqlite::params![(SCHEMA_VERSION + 1).to_string(), SCHEMA_VERSION_CONFIG],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(DmError::UnsupportedSchemaVersion(_))
+        ));
+    }
+}
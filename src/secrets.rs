@@ -0,0 +1,372 @@
+use crate::crypto::{decrypt_content, Encryption, Verification};
+use crate::error::DmError;
+use crate::settings::Compression;
+use crate::vault::Vault;
+use crate::SECRET_HISTORY_LIMIT_CONFIG;
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// Name and tags of a secret stored in the vault.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SecretInfo {
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+/// A stored revision of a secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SecretVersion {
+    pub version: i64,
+    pub created_at: String,
+    pub current: bool,
+}
+
+impl Vault {
+    /// Encrypts and stores a new secret. `tags` is a comma-separated list.
+    pub fn add_secret(&mut self, name: &str, value: &[u8], tags: &str) -> Result<(), DmError> {
+        let encryption = Self::encryption(&self.conn)?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::insert_secret(&tx, &encryption, &audit_key, name, value, tags)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Lists secrets sorted by name, keeping only those with any of the comma-separated `tags`.
+    pub fn list_secrets(&self, tags: &str) -> Result<Vec<SecretInfo>, DmError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT name, tags FROM secrets ORDER BY name")?;
+        let secret_iter = stmt.query_map([], |row| {
+            let name: String = row.get(0)?;
+            let tags: String = row.get(1)?;
+            Ok((name, tags))
+        })?;
+
+        let mut secrets = Vec::new();
+        for secret in secret_iter {
+            let (name, secret_tags) = secret?;
+            // Filter by tags if specified
+            if !matches_tags(&secret_tags, tags) {
+                continue;
+            }
+            secrets.push(SecretInfo {
+                name,
+                tags: secret_tags
+                    .split(',')
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect(),
+            });
+        }
+        Ok(secrets)
+    }
+
+    /// Replaces the value of a secret. Tags are kept when `tags` is empty.
+    pub fn update_secret(&mut self, name: &str, value: &[u8], tags: &str) -> Result<(), DmError> {
+        let encryption = Self::encryption(&self.conn)?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::replace_secret(&tx, &encryption, &audit_key, name, value, tags)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes a secret with its history. Returns `false` if there was no such secret.
+    pub fn remove_secret(&mut self, name: &str) -> Result<bool, DmError> {
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let rows_affected = tx.execute(
+            "DELETE FROM secrets WHERE name = ?1",
+            rusqlite::params![name],
+        )?;
+        tx.execute(
+            "DELETE FROM secret_versions WHERE name = ?1",
+            rusqlite::params![name],
+        )?;
+        if rows_affected > 0 {
+            Self::audit(&tx, &audit_key, "secret_remove", name)?;
+        }
+        tx.commit()?;
+        Ok(rows_affected > 0)
+    }
+
+    /// Decrypts the current value of a secret, or the given version of it.
+    pub fn show_secret(&self, name: &str, version: Option<i64>) -> Result<Vec<u8>, DmError> {
+        let value = Self::decrypt_secret(&self.conn, name, version, &self.verification()?)?;
+        Self::audit(&self.conn, &self.audit_key()?, "secret_show", name)?;
+        Ok(value)
+    }
+
+    /// Decrypts a secret in `conn` checked with `verification`, without an audit entry.
+    pub(crate) fn decrypt_secret(
+        conn: &Connection,
+        name: &str,
+        version: Option<i64>,
+        verification: &Verification,
+    ) -> Result<Vec<u8>, DmError> {
+        // Get the encrypted secret
+        let (encrypted_value, compression) = match version {
+            Some(version) => Self::get_secret_version(conn, name, version)?,
+            None => {
+                let (body, compression): (Vec<u8>, Option<String>) = conn
+                    .query_row(
+                        "SELECT body, compression FROM secrets WHERE name = ?1",
+                        rusqlite::params![name],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .map_err(|_| DmError::SecretNotInStorage(name.to_string()))?;
+                (body, Compression::from_column(compression.as_deref())?)
+            }
+        };
+
+        let verification = verification
+            .clone()
+            .with_subject(format!("secret '{}'", name));
+        decrypt_content(&encrypted_value, compression, &verification)
+    }
+
+    /// Decrypts all secrets with any of the comma-separated `tags`, sorted by name.
+    pub fn decrypt_secrets(&self, tags: &str) -> Result<Vec<(SecretInfo, Vec<u8>)>, DmError> {
+        let mut secrets = Vec::new();
+        for secret in self.list_secrets(tags)? {
+            let value = self.show_secret(&secret.name, None)?;
+            secrets.push((secret, value));
+        }
+        Ok(secrets)
+    }
+
+    /// Lists stored versions of a secret, oldest first.
+    pub fn secret_history(&self, name: &str) -> Result<Vec<SecretVersion>, DmError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT version, created_at FROM secret_versions
+             WHERE name = ?1 ORDER BY version",
+        )?;
+        let version_iter = stmt.query_map(rusqlite::params![name], |row| {
+            Ok(SecretVersion {
+                version: row.get(0)?,
+                created_at: row.get(1)?,
+                current: false,
+            })
+        })?;
+
+        let mut versions = Vec::new();
+        for version in version_iter {
+            versions.push(version?);
+        }
+
+        match versions.last_mut() {
+            Some(last) => last.current = true,
+            None => return Err(DmError::SecretNotInStorage(name.to_string())),
+        }
+        Ok(versions)
+    }
+
+    /// Restores a previous version of a secret and returns the number of the new version.
+    pub fn rollback_secret(&mut self, name: &str, version: i64) -> Result<i64, DmError> {
+        let (body, compression) = Self::get_secret_version(&self.conn, name, version)?;
+
+        // Rollback is recorded as a new version so the history stays linear
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let rows_affected = tx.execute(
+            "UPDATE secrets SET body = ?1, compression = ?2 WHERE name = ?3",
+            rusqlite::params![&body, compression.to_column(), name],
+        )?;
+        if rows_affected == 0 {
+            return Err(DmError::SecretNotInStorage(name.to_string()));
+        }
+        let new_version = Self::record_secret_version(&tx, name, &body, compression)?;
+        Self::audit(&tx, &audit_key, "secret_rollback", name)?;
+        tx.commit()?;
+        Ok(new_version)
+    }
+
+    /// Number of versions kept per secret, 0 means all are kept.
+    pub fn secret_history_limit(&self) -> Result<i64, DmError> {
+        Self::get_secret_history_limit(&self.conn)
+    }
+
+    /// Sets how many versions are kept per secret and prunes older ones.
+    pub fn set_secret_history_limit(&mut self, count: i64) -> Result<(), DmError> {
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::set_config(&tx, SECRET_HISTORY_LIMIT_CONFIG, &count.max(0).to_string())?;
+        Self::prune_secret_versions(&tx)?;
+        Self::audit(
+            &tx,
+            &audit_key,
+            "secret_retention",
+            &count.max(0).to_string(),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Encrypts and inserts a new secret together with its first version.
+    pub(crate) fn insert_secret(
+        conn: &Connection,
+        encryption: &Encryption,
+        audit_key: &[u8],
+        name: &str,
+        value: &[u8],
+        tags: &str,
+    ) -> Result<(), DmError> {
+        // Check if secret already exists
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(id) FROM secrets WHERE name = ?1",
+            rusqlite::params![name],
+            |row| row.get(0),
+        )?;
+
+        if count > 0 {
+            return Err(DmError::SecretAlreadyExists(name.to_string()));
+        }
+
+        let encrypted_value = encryption.encrypt(value)?;
+        let compression = encryption.settings.compression;
+        conn.execute(
+            "INSERT INTO secrets (name, body, tags, compression) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![name, encrypted_value, tags, compression.to_column()],
+        )?;
+        Self::record_secret_version(conn, name, &encrypted_value, compression)?;
+        Self::audit(conn, audit_key, "secret_add", name)
+    }
+
+    /// Encrypts a new value for an existing secret and records it as a new version.
+    pub(crate) fn replace_secret(
+        conn: &Connection,
+        encryption: &Encryption,
+        audit_key: &[u8],
+        name: &str,
+        value: &[u8],
+        tags: &str,
+    ) -> Result<(), DmError> {
+        // Check if secret exists
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(id) FROM secrets WHERE name = ?1",
+            rusqlite::params![name],
+            |row| row.get(0),
+        )?;
+
+        if count == 0 {
+            return Err(DmError::SecretNotInStorage(name.to_string()));
+        }
+
+        let encrypted_value = encryption.encrypt(value)?;
+        let compression = encryption.settings.compression;
+        conn.execute(
+            "UPDATE secrets SET body = ?1, compression = ?2 WHERE name = ?3",
+            rusqlite::params![encrypted_value, compression.to_column(), name],
+        )?;
+        if !tags.is_empty() {
+            conn.execute(
+                "UPDATE secrets SET tags = ?1 WHERE name = ?2",
+                rusqlite::params![tags, name],
+            )?;
+        }
+        Self::record_secret_version(conn, name, &encrypted_value, compression)?;
+        Self::audit(conn, audit_key, "secret_update", name)
+    }
+
+    pub(crate) fn get_secret_version(
+        conn: &Connection,
+        name: &str,
+        version: i64,
+    ) -> Result<(Vec<u8>, Compression), DmError> {
+        let (body, compression): (Vec<u8>, Option<String>) = conn
+            .query_row(
+                "SELECT body, compression FROM secret_versions WHERE name = ?1 AND version = ?2",
+                rusqlite::params![name, version],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|_| DmError::SecretVersionNotFound(name.to_string(), version))?;
+        Ok((body, Compression::from_column(compression.as_deref())?))
+    }
+
+    pub(crate) fn record_secret_version(
+        conn: &Connection,
+        name: &str,
+        body: &[u8],
+        compression: Compression,
+    ) -> Result<i64, DmError> {
+        conn.execute(
+            "INSERT INTO secret_versions (name, version, body, compression)
+             SELECT ?1, COALESCE(MAX(version), 0) + 1, ?2, ?3
+             FROM secret_versions WHERE name = ?1",
+            rusqlite::params![name, body, compression.to_column()],
+        )?;
+        let version: i64 = conn.query_row(
+            "SELECT MAX(version) FROM secret_versions WHERE name = ?1",
+            rusqlite::params![name],
+            |row| row.get(0),
+        )?;
+        Self::prune_secret_versions(conn)?;
+        Ok(version)
+    }
+
+    fn prune_secret_versions(conn: &Connection) -> Result<(), DmError> {
+        let limit = Self::get_secret_history_limit(conn)?;
+        if limit == 0 {
+            return Ok(());
+        }
+
+        // The current version is always the newest one, so it is never pruned
+        conn.execute(
+            "DELETE FROM secret_versions WHERE version <= (
+                SELECT MAX(v.version) FROM secret_versions v
+                WHERE v.name = secret_versions.name
+             ) - ?1",
+            rusqlite::params![limit],
+        )?;
+        Ok(())
+    }
+
+    fn get_secret_history_limit(conn: &Connection) -> Result<i64, DmError> {
+        let limit = Self::get_config(conn, SECRET_HISTORY_LIMIT_CONFIG)?;
+        Ok(limit.and_then(|l| l.parse().ok()).unwrap_or(0))
+    }
+}
+
+/// Checks whether comma-separated `secret_tags` share a tag with `filter`.
+/// An empty filter matches everything.
+pub(crate) fn matches_tags(secret_tags: &str, filter: &str) -> bool {
+    filter.is_empty()
+        || secret_tags
+            .split(',')
+            .any(|t| filter.split(',').any(|tag| tag.trim() == t.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::migrate;
+
+    #[test]
+    fn test_secret_version_retention() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Vault::set_config(&conn, SECRET_HISTORY_LIMIT_CONFIG, "2").unwrap();
+
+        for body in [b"one", b"two", b"tri"] {
+            Vault::record_secret_version(&conn, "api_key", body, Compression::None).unwrap();
+        }
+
+        assert!(matches!(
+            Vault::get_secret_version(&conn, "api_key", 1),
+            Err(DmError::SecretVersionNotFound(_, 1))
+        ));
+        assert_eq!(
+            Vault::get_secret_version(&conn, "api_key", 3).unwrap(),
+            (b"tri".to_vec(), Compression::None)
+        );
+    }
+
+    #[test]
+    fn test_matches_tags() {
+        assert!(matches_tags("production,api", ""));
+        assert!(matches_tags("production, api", "api"));
+        assert!(matches_tags("production,api", "staging,production"));
+        assert!(!matches_tags("production,api", "staging"));
+    }
+}
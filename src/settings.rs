@@ -0,0 +1,274 @@
+use crate::crypto::{Encryption, Verification};
+use crate::error::DmError;
+use crate::vault::Vault;
+use crate::{ARMOR_CONFIG, COMPRESSION_CONFIG, GPG_KEY_HASH_CONFIG, SIGNATURES_CONFIG};
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// Compression applied to content before it is encrypted. Recorded with every
+/// stored row, so changing the vault setting never affects existing rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// Parses the value of a `compression` column, where NULL means no compression.
+    pub(crate) fn from_column(value: Option<&str>) -> Result<Self, DmError> {
+        match value {
+            None | Some("none") => Ok(Compression::None),
+            Some("zstd") => Ok(Compression::Zstd),
+            Some(other) => Err(DmError::InvalidInput(format!(
+                "Unsupported compression '{}', please upgrade dark-matter",
+                other
+            ))),
+        }
+    }
+
+    pub(crate) fn to_column(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+}
+
+/// What happens to decrypted content that is unsigned or signed by a key that is
+/// not an allowed signer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignaturePolicy {
+    /// Refuse the content
+    #[default]
+    Require,
+    /// Pass a warning to the handler set with [`Vault::set_warning_handler`] and use the content
+    Warn,
+}
+
+impl SignaturePolicy {
+    fn parse(value: Option<&str>) -> Result<Self, DmError> {
+        match value {
+            None | Some("require") => Ok(SignaturePolicy::Require),
+            Some("warn") => Ok(SignaturePolicy::Warn),
+            Some(other) => Err(DmError::InvalidInput(format!(
+                "Unsupported signature policy '{}'",
+                other
+            ))),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SignaturePolicy::Require => "require",
+            SignaturePolicy::Warn => "warn",
+        }
+    }
+}
+
+/// How content is stored and checked. Defaults keep vaults readable by older releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct VaultSettings {
+    /// Store ciphertext as ASCII armor instead of binary OpenPGP, about a third larger
+    pub armor: bool,
+    pub compression: Compression,
+    pub signatures: SignaturePolicy,
+}
+
+impl Default for VaultSettings {
+    fn default() -> Self {
+        VaultSettings {
+            armor: true,
+            compression: Compression::None,
+            signatures: SignaturePolicy::Require,
+        }
+    }
+}
+
+/// Signers and policy that content decrypted from a vault is checked with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VerificationSettings {
+    pub(crate) signers: Vec<String>,
+    /// Signers were read from the vault instead of pinned outside of it
+    pub(crate) from_vault: bool,
+    pub(crate) policy: SignaturePolicy,
+}
+
+impl Vault {
+    /// Settings used for content stored from now on.
+    pub fn settings(&self) -> Result<VaultSettings, DmError> {
+        Self::get_settings(&self.conn)
+    }
+
+    /// Changes settings. Armor and compression apply to content stored from now on,
+    /// see [`Vault::compact`] for existing content.
+    pub fn set_settings(&mut self, settings: VaultSettings) -> Result<(), DmError> {
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::set_config(&tx, ARMOR_CONFIG, &settings.armor.to_string())?;
+        Self::set_config(
+            &tx,
+            COMPRESSION_CONFIG,
+            settings.compression.to_column().unwrap_or("none"),
+        )?;
+        Self::set_config(&tx, SIGNATURES_CONFIG, settings.signatures.as_str())?;
+        let target = format!(
+            "armor={} compression={} signatures={}",
+            settings.armor,
+            settings.compression.to_column().unwrap_or("none"),
+            settings.signatures.as_str()
+        );
+        Self::audit(&tx, &audit_key, "settings", &target)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Rewrites every stored secret and file with the current settings and shrinks
+    /// the database file. Returns the number of rewritten entries.
+    pub fn compact(&mut self) -> Result<usize, DmError> {
+        let verification = self.verification()?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let encryption = Self::encryption(&tx)?;
+        let count = Self::reencrypt_all(&tx, &encryption, &verification)?;
+        Self::audit(&tx, &audit_key, "compact", "vault")?;
+        tx.commit()?;
+
+        // Space of replaced rows is only returned to the file system by VACUUM
+        self.conn.execute("VACUUM", [])?;
+        Ok(count)
+    }
+
+    pub(crate) fn get_settings(conn: &Connection) -> Result<VaultSettings, DmError> {
+        let defaults = VaultSettings::default();
+        let armor = match Self::get_config(conn, ARMOR_CONFIG)? {
+            Some(armor) => armor == "true",
+            None => defaults.armor,
+        };
+        let compression =
+            Compression::from_column(Self::get_config(conn, COMPRESSION_CONFIG)?.as_deref())?;
+        let signatures =
+            SignaturePolicy::parse(Self::get_config(conn, SIGNATURES_CONFIG)?.as_deref())?;
+        Ok(VaultSettings {
+            armor,
+            compression,
+            signatures,
+        })
+    }
+
+    /// Recipients and settings new content is encrypted with.
+    pub(crate) fn encryption(conn: &Connection) -> Result<Encryption, DmError> {
+        Ok(Encryption {
+            recipients: Self::get_recipients(conn)?,
+            settings: Self::get_settings(conn)?,
+        })
+    }
+
+    /// Signers accepted on content decrypted by this handle, see [`Vault::allowed_signers`].
+    /// Signers read from the vault only count if their secret key is in the keyring, anyone
+    /// who can write the vault could otherwise name any public key there.
+    pub(crate) fn verification(&self) -> Result<Verification, DmError> {
+        let settings =
+            Self::verification_settings(&self.conn, self.signers.as_deref(), self.policy)?;
+        let mut verification =
+            Verification::new(&settings.signers, settings.policy, settings.from_vault)?;
+        verification.warn = self.warn.clone();
+        Ok(verification)
+    }
+
+    /// Allowed signers and signature policy of the vault behind `conn`. Values pinned
+    /// outside of the vault take precedence over those stored in it.
+    pub(crate) fn verification_settings(
+        conn: &Connection,
+        pinned_signers: Option<&[String]>,
+        pinned_policy: Option<SignaturePolicy>,
+    ) -> Result<VerificationSettings, DmError> {
+        let policy = match pinned_policy {
+            Some(policy) => policy,
+            None => Self::get_settings(conn)?.signatures,
+        };
+        Ok(VerificationSettings {
+            signers: Self::allowed_signers(conn, pinned_signers)?,
+            from_vault: pinned_signers.is_none(),
+            policy,
+        })
+    }
+
+    /// The `pinned` signers if set, otherwise only the vault key. Other recipients are
+    /// never trusted by default, anyone who can write the vault can add themselves.
+    pub(crate) fn allowed_signers(
+        conn: &Connection,
+        pinned: Option<&[String]>,
+    ) -> Result<Vec<String>, DmError> {
+        if let Some(pinned) = pinned {
+            return Ok(pinned.to_vec());
+        }
+        Ok(Self::get_config(conn, GPG_KEY_HASH_CONFIG)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::migrate;
+
+    #[test]
+    fn test_settings() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(
+            Vault::get_settings(&conn).unwrap(),
+            VaultSettings::default()
+        );
+
+        Vault::set_config(&conn, ARMOR_CONFIG, "false").unwrap();
+        Vault::set_config(&conn, COMPRESSION_CONFIG, "zstd").unwrap();
+        assert_eq!(
+            Vault::get_settings(&conn).unwrap(),
+            VaultSettings {
+                armor: false,
+                compression: Compression::Zstd,
+                signatures: SignaturePolicy::Require,
+            }
+        );
+
+        assert_eq!(Compression::from_column(None).unwrap(), Compression::None);
+        assert!(Compression::from_column(Some("lz4")).is_err());
+    }
+
+    #[test]
+    fn test_allowed_signers() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Vault::set_config(&conn, GPG_KEY_HASH_CONFIG, "VAULT").unwrap();
+        for key_hash in ["VAULT", "INJECTED"] {
+            conn.execute(
+                "INSERT INTO recipients (key_hash) VALUES (?1)",
+                rusqlite::params![key_hash],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(Vault::allowed_signers(&conn, None).unwrap(), ["VAULT"]);
+        let pinned = ["ALICE".to_string(), "BOB".to_string()];
+        assert_eq!(
+            Vault::allowed_signers(&conn, Some(&pinned)).unwrap(),
+            pinned
+        );
+
+        // A policy relaxed inside the vault does not override one pinned outside
+        Vault::set_config(&conn, SIGNATURES_CONFIG, "warn").unwrap();
+        let settings = Vault::verification_settings(&conn, None, None).unwrap();
+        assert_eq!(settings.policy, SignaturePolicy::Warn);
+        assert!(settings.from_vault);
+        let settings =
+            Vault::verification_settings(&conn, Some(&pinned), Some(SignaturePolicy::Require))
+                .unwrap();
+        assert_eq!(settings.policy, SignaturePolicy::Require);
+        assert!(!settings.from_vault);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::DmError;
+use crate::vault::Vault;
+
+/// Piece of a parsed template.
+#[derive(Debug, PartialEq, Eq)]
+enum Segment<'a> {
+    Text(&'a str),
+    Secret(String),
+}
+
+impl Vault {
+    /// Replaces `{{ secret "name" }}` placeholders in `template` with decrypted secret values.
+    /// Fails listing every unknown name before anything is decrypted.
+    pub fn render_template(&self, template: &str) -> Result<Vec<u8>, DmError> {
+        let segments = parse(template)?;
+
+        let mut unknown = Vec::new();
+        for segment in &segments {
+            if let Segment::Secret(name) = segment {
+                let count: i64 = self.conn.query_row(
+                    "SELECT COUNT(id) FROM secrets WHERE name = ?1",
+                    rusqlite::params![name],
+                    |row| row.get(0),
+                )?;
+                if count == 0 && !unknown.contains(name) {
+                    unknown.push(name.clone());
+                }
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(DmError::UnknownSecrets(unknown));
+        }
+
+        let mut output = Vec::with_capacity(template.len());
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => output.extend_from_slice(text.as_bytes()),
+                Segment::Secret(name) => output.extend(self.show_secret(&name, None)?),
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Splits a template into text and secret placeholders. Other `{{ ... }}`
+/// expressions are kept as text, so templates of other tools pass through.
+fn parse(template: &str) -> Result<Vec<Segment<'_>>, DmError> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        let expression = rest[start + 2..start + len].trim();
+
+        match expression.strip_prefix("secret") {
+            Some(argument) if argument.starts_with(char::is_whitespace) => {
+                let name = parse_name(argument.trim()).ok_or_else(|| {
+                    let line = template[..template.len() - rest.len() + start]
+                        .matches('\n')
+                        .count()
+                        + 1;
+                    DmError::InvalidInput(format!(
+                        "Invalid placeholder '{{{{ {} }}}}' on line {}, expected {{{{ secret \"name\" }}}}",
+                        expression, line
+                    ))
+                })?;
+                segments.push(Segment::Text(&rest[..start]));
+                segments.push(Segment::Secret(name));
+            }
+            _ => segments.push(Segment::Text(&rest[..start + len + 2])),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    segments.push(Segment::Text(rest));
+
+    Ok(segments)
+}
+
+/// Parses a double-quoted name where `\"` and `\\` are escapes.
+fn parse_name(argument: &str) -> Option<String> {
+    let inner = argument.strip_prefix('"')?.strip_suffix('"')?;
+
+    let mut name = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.push(chars.next()?),
+            '"' => return None,
+            c => name.push(c),
+        }
+    }
+    if name.is_empty() {
+        return None;
+    }
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let segments = parse("user={{ user }}\npass={{secret \"db_password\"}}\n").unwrap();
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Text("user={{ user }}"),
+                Segment::Text("\npass="),
+                Segment::Secret("db_password".to_string()),
+                Segment::Text("\n"),
+            ]
+        );
+
+        assert!(matches!(
+            parse("a\n{{ secret db_password }}"),
+            Err(DmError::InvalidInput(message)) if message.contains("line 2")
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+use crate::crypto::{
+    decrypt_content, random_bytes, verify_key, Encryption, Verification, WarningHandler,
+};
+use crate::error::DmError;
+use crate::schema::migrate;
+use crate::settings::{Compression, SignaturePolicy};
+use crate::{CONTENT_HASH_KEY_CONFIG, DB_NAME, GPG_KEY_HASH_CONFIG};
+use base64::Engine;
+use rusqlite::{Connection, OptionalExtension};
+use std::cell::OnceCell;
+use std::io::{self, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An open vault database.
+pub struct Vault {
+    pub(crate) conn: Connection,
+    path: PathBuf,
+    pub(crate) signers: Option<Vec<String>>,
+    pub(crate) policy: Option<SignaturePolicy>,
+    pub(crate) warn: Option<WarningHandler>,
+    /// Decrypted content hash key, see [`Vault::content_hash_key`]
+    content_key: OnceCell<Vec<u8>>,
+}
+
+impl Vault {
+    /// Creates a new vault at `path` encrypted to the given GPG key.
+    pub fn init(path: impl AsRef<Path>, key_hash: &str) -> Result<Self, DmError> {
+        let path = path.as_ref();
+
+        // Check if database already exists
+        if path.exists() {
+            return Err(DmError::DatabaseAlreadyExists);
+        }
+
+        // Check if GPG key exists
+        verify_key(key_hash)?;
+
+        // Create vault
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+
+        // Save hash of GPG key in configuration
+        conn.execute(
+            "INSERT INTO config (key, value) VALUES (?1, ?2)",
+            rusqlite::params![GPG_KEY_HASH_CONFIG, key_hash],
+        )?;
+        conn.execute(
+            "INSERT INTO recipients (key_hash) VALUES (?1)",
+            rusqlite::params![key_hash],
+        )?;
+        let vault = Vault {
+            conn,
+            path: path.to_path_buf(),
+            signers: None,
+            policy: None,
+            warn: None,
+            content_key: OnceCell::new(),
+        };
+        Self::audit(&vault.conn, &vault.audit_key()?, "init", key_hash)?;
+        Ok(vault)
+    }
+
+    /// Opens an existing vault, upgrading its schema if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, DmError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(DmError::DatabaseNotFound);
+        }
+
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(Vault {
+            conn,
+            path: path.to_path_buf(),
+            signers: None,
+            policy: None,
+            warn: None,
+            content_key: OnceCell::new(),
+        })
+    }
+
+    /// Finds a vault by walking up from `start` to the first directory containing [`DB_NAME`].
+    pub fn discover(start: impl AsRef<Path>) -> Result<PathBuf, DmError> {
+        start
+            .as_ref()
+            .ancestors()
+            .map(|dir| dir.join(DB_NAME))
+            .find(|path| path.is_file())
+            .ok_or(DmError::DatabaseNotFound)
+    }
+
+    /// Path of the vault database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Only accepts content signed by these keys instead of the vault key. Needed
+    /// where other recipients write the vault too, their keys are not trusted
+    /// by default.
+    pub fn set_allowed_signers(&mut self, signers: Vec<String>) {
+        self.signers = Some(signers);
+    }
+
+    /// Applies this signature policy instead of the one stored in the vault, which
+    /// anyone who can write the vault could relax.
+    pub fn set_signature_policy(&mut self, policy: SignaturePolicy) {
+        self.policy = Some(policy);
+    }
+
+    /// Called with a message for every unverified entry that is used anyway
+    /// because of [`SignaturePolicy::Warn`](crate::SignaturePolicy::Warn).
+    pub fn set_warning_handler(&mut self, handler: impl Fn(&str) + Send + Sync + 'static) {
+        self.warn = Some(Arc::new(handler));
+    }
+
+    /// Hash of the GPG key the vault was created with.
+    pub fn key_hash(&self) -> Result<String, DmError> {
+        let key_hash: String = self.conn.query_row(
+            "SELECT value FROM config WHERE key = ?1",
+            rusqlite::params![GPG_KEY_HASH_CONFIG],
+            |row| row.get(0),
+        )?;
+        Ok(key_hash)
+    }
+
+    /// Keys every secret and file is encrypted to, vault key first.
+    pub fn recipients(&self) -> Result<Vec<String>, DmError> {
+        Self::get_recipients(&self.conn)
+    }
+
+    /// Adds a recipient and re-encrypts the vault, returning the number of re-encrypted entries.
+    pub fn add_recipient(&mut self, key_hash: &str) -> Result<usize, DmError> {
+        verify_key(key_hash)?;
+
+        let verification = self.verification()?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let recipients = Self::insert_recipient(&tx, key_hash)?;
+        let encryption = Encryption {
+            recipients,
+            settings: Self::get_settings(&tx)?,
+        };
+        let count = Self::reencrypt_all(&tx, &encryption, &verification)?;
+        Self::audit(&tx, &audit_key, "recipient_add", key_hash)?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Removes a recipient and re-encrypts the vault, returning the number of re-encrypted entries.
+    pub fn remove_recipient(&mut self, key_hash: &str) -> Result<usize, DmError> {
+        let verification = self.verification()?;
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        let recipients = Self::delete_recipient(&tx, key_hash)?;
+        let encryption = Encryption {
+            recipients,
+            settings: Self::get_settings(&tx)?,
+        };
+        let count = Self::reencrypt_all(&tx, &encryption, &verification)?;
+        Self::audit(&tx, &audit_key, "recipient_remove", key_hash)?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Replaces the vault key and re-encrypts the vault, returning the number of re-encrypted entries.
+    pub fn rotate_key(&mut self, new_key_hash: &str) -> Result<usize, DmError> {
+        verify_key(new_key_hash)?;
+
+        let old_key_hash = self.key_hash()?;
+        if old_key_hash == new_key_hash {
+            return Ok(0);
+        }
+
+        let verification = self.verification()?;
+        let mut recipients = self.recipients()?;
+        recipients.retain(|r| r != &old_key_hash && r != new_key_hash);
+        recipients.insert(0, new_key_hash.to_string());
+
+        // Any failure drops the transaction and leaves the vault untouched
+        let audit_key = self.audit_key()?;
+        let tx = self.conn.transaction()?;
+        Self::replace_vault_key(&tx, &old_key_hash, new_key_hash)?;
+        let encryption = Encryption {
+            recipients,
+            settings: Self::get_settings(&tx)?,
+        };
+        let count = Self::reencrypt_all(&tx, &encryption, &verification)?;
+        Self::audit(&tx, &audit_key, "key_rotate", new_key_hash)?;
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Adds `key_hash` to the recipients and returns all of them, vault key first.
+    fn insert_recipient(conn: &Connection, key_hash: &str) -> Result<Vec<String>, DmError> {
+        let mut recipients = Self::get_recipients(conn)?;
+        if recipients.iter().any(|r| r == key_hash) {
+            return Err(DmError::RecipientAlreadyExists(key_hash.to_string()));
+        }
+
+        conn.execute(
+            "INSERT INTO recipients (key_hash) VALUES (?1)",
+            rusqlite::params![key_hash],
+        )?;
+        recipients.push(key_hash.to_string());
+        Ok(recipients)
+    }
+
+    /// Removes `key_hash` from the recipients and returns the remaining ones, vault key first.
+    /// The vault key itself cannot be removed.
+    fn delete_recipient(conn: &Connection, key_hash: &str) -> Result<Vec<String>, DmError> {
+        if Self::get_config(conn, GPG_KEY_HASH_CONFIG)?.as_deref() == Some(key_hash) {
+            return Err(DmError::RecipientIsVaultKey(key_hash.to_string()));
+        }
+
+        let rows_affected = conn.execute(
+            "DELETE FROM recipients WHERE key_hash = ?1",
+            rusqlite::params![key_hash],
+        )?;
+        if rows_affected == 0 {
+            return Err(DmError::RecipientNotFound(key_hash.to_string()));
+        }
+        Self::get_recipients(conn)
+    }
+
+    /// Makes `new_key_hash` the vault key in place of `old_key_hash`, keeping it the
+    /// first recipient.
+    fn replace_vault_key(
+        conn: &Connection,
+        old_key_hash: &str,
+        new_key_hash: &str,
+    ) -> Result<(), DmError> {
+        conn.execute(
+            "UPDATE config SET value = ?1 WHERE key = ?2",
+            rusqlite::params![new_key_hash, GPG_KEY_HASH_CONFIG],
+        )?;
+        // The new key may have been a recipient already
+        conn.execute(
+            "DELETE FROM recipients WHERE key_hash = ?1",
+            rusqlite::params![new_key_hash],
+        )?;
+        let rows_affected = conn.execute(
+            "UPDATE recipients SET key_hash = ?1 WHERE key_hash = ?2",
+            rusqlite::params![new_key_hash, old_key_hash],
+        )?;
+        if rows_affected == 0 {
+            conn.execute(
+                "INSERT INTO recipients (key_hash) VALUES (?1)",
+                rusqlite::params![new_key_hash],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_recipients(conn: &Connection) -> Result<Vec<String>, DmError> {
+        let mut stmt = conn.prepare("SELECT key_hash FROM recipients ORDER BY rowid")?;
+        let recipient_iter = stmt.query_map([], |row| row.get(0))?;
+
+        let mut recipients = Vec::new();
+        for recipient in recipient_iter {
+            recipients.push(recipient?);
+        }
+        Ok(recipients)
+    }
+
+    /// Key of file content hashes, created on first use. It is stored encrypted
+    /// like everything else, so hashes cannot be checked without the GPG key.
+    pub(crate) fn content_hash_key(&self) -> Result<Vec<u8>, DmError> {
+        if let Some(key) = self.content_key.get() {
+            return Ok(key.clone());
+        }
+
+        let verification = self
+            .verification()?
+            .with_subject("content hash key".to_string());
+        let key = match Self::get_content_hash_key(&self.conn, &verification)? {
+            Some(key) => key,
+            None => {
+                let key = random_bytes(32)?;
+                Self::set_content_hash_key(&self.conn, &key, &Self::encryption(&self.conn)?)?;
+                key
+            }
+        };
+        Ok(self.content_key.get_or_init(|| key).clone())
+    }
+
+    pub(crate) fn get_config(conn: &Connection, key: &str) -> Result<Option<String>, DmError> {
+        let value = conn
+            .query_row(
+                "SELECT value FROM config WHERE key = ?1",
+                rusqlite::params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    pub(crate) fn set_config(conn: &Connection, key: &str, value: &str) -> Result<(), DmError> {
+        conn.execute(
+            "INSERT OR REPLACE INTO config (key, value) VALUES (?1, ?2)",
+            rusqlite::params![key, value],
+        )?;
+        Ok(())
+    }
+
+    fn get_content_hash_key(
+        conn: &Connection,
+        verification: &Verification,
+    ) -> Result<Option<Vec<u8>>, DmError> {
+        let Some(encrypted_key) = Self::get_config(conn, CONTENT_HASH_KEY_CONFIG)? else {
+            return Ok(None);
+        };
+        let encrypted_key = base64::engine::general_purpose::STANDARD
+            .decode(encrypted_key)
+            .map_err(|e| DmError::InvalidInput(format!("Corrupt content hash key: {}", e)))?;
+        Ok(Some(decrypt_content(
+            &encrypted_key,
+            Compression::None,
+            verification,
+        )?))
+    }
+
+    fn set_content_hash_key(
+        conn: &Connection,
+        key: &[u8],
+        encryption: &Encryption,
+    ) -> Result<(), DmError> {
+        let encrypted_key = encryption.encrypt_uncompressed(key)?;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(encrypted_key);
+        Self::set_config(conn, CONTENT_HASH_KEY_CONFIG, &encoded)
+    }
+
+    pub(crate) fn reencrypt_all(
+        conn: &Connection,
+        encryption: &Encryption,
+        verification: &Verification,
+    ) -> Result<usize, DmError> {
+        // Decrypt every stored body, including history, and encrypt it again
+        let mut count = 0;
+        for table in ["secrets", "secret_versions"] {
+            let mut stmt = conn.prepare(&format!("SELECT id FROM {} ORDER BY id", table))?;
+            let ids = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?;
+
+            for id in ids {
+                let (body, compression): (Vec<u8>, Option<String>) = conn.query_row(
+                    &format!("SELECT body, compression FROM {} WHERE id = ?1", table),
+                    rusqlite::params![id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                let compression = Compression::from_column(compression.as_deref())?;
+                let content = decrypt_content(&body, compression, verification)?;
+                let body = encryption.encrypt(&content)?;
+                conn.execute(
+                    &format!(
+                        "UPDATE {} SET body = ?1, compression = ?2 WHERE id = ?3",
+                        table
+                    ),
+                    rusqlite::params![body, encryption.settings.compression.to_column(), id],
+                )?;
+                count += 1;
+            }
+        }
+
+        // File content is streamed, it may not fit in memory
+        let mut stmt = conn.prepare("SELECT id, compression FROM file_versions ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(i64, Option<String>)>, _>>()?;
+        for (id, compression) in rows {
+            let compression = Compression::from_column(compression.as_deref())?;
+            let ciphertext = Self::read_chunks(conn, id)?;
+            let mut reencrypted = tempfile::tempfile()?;
+            encryption.reencrypt_stream(
+                &ciphertext,
+                &mut reencrypted,
+                compression,
+                verification,
+                io::sink(),
+            )?;
+            reencrypted.rewind()?;
+
+            conn.execute(
+                "UPDATE file_versions SET compression = ?1 WHERE id = ?2",
+                rusqlite::params![encryption.settings.compression.to_column(), id],
+            )?;
+            conn.execute(
+                "DELETE FROM file_chunks WHERE version_id = ?1",
+                rusqlite::params![id],
+            )?;
+            Self::write_chunks(conn, id, &reencrypted)?;
+            count += 1;
+        }
+
+        if let Some(key) = Self::get_content_hash_key(conn, verification)? {
+            Self::set_content_hash_key(conn, &key, encryption)?;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_discover() {
+        let temp_dir = TempDir::new().unwrap();
+        let nested = temp_dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+
+        assert!(matches!(
+            Vault::discover(&nested),
+            Err(DmError::DatabaseNotFound)
+        ));
+
+        let db_path = temp_dir.path().join("a").join(DB_NAME);
+        fs::write(&db_path, b"").unwrap();
+        assert_eq!(Vault::discover(&nested).unwrap(), db_path);
+    }
+
+    #[test]
+    fn test_replace_vault_key() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Vault::set_config(&conn, GPG_KEY_HASH_CONFIG, "OLD").unwrap();
+        for key_hash in ["OLD", "ALICE", "NEW", "BOB"] {
+            conn.execute(
+                "INSERT INTO recipients (key_hash) VALUES (?1)",
+                rusqlite::params![key_hash],
+            )
+            .unwrap();
+        }
+
+        Vault::replace_vault_key(&conn, "OLD", "NEW").unwrap();
+        assert_eq!(
+            Vault::get_recipients(&conn).unwrap(),
+            ["NEW", "ALICE", "BOB"]
+        );
+        assert_eq!(
+            Vault::get_config(&conn, GPG_KEY_HASH_CONFIG).unwrap(),
+            Some("NEW".to_string())
+        );
+    }
+
+    #[test]
+    fn test_add_and_remove_recipients() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        Vault::set_config(&conn, GPG_KEY_HASH_CONFIG, "VAULT").unwrap();
+        Vault::insert_recipient(&conn, "VAULT").unwrap();
+
+        assert_eq!(
+            Vault::insert_recipient(&conn, "ALICE").unwrap(),
+            ["VAULT", "ALICE"]
+        );
+        Vault::insert_recipient(&conn, "BOB").unwrap();
+        assert!(matches!(
+            Vault::insert_recipient(&conn, "ALICE"),
+            Err(DmError::RecipientAlreadyExists(_))
+        ));
+
+        assert!(matches!(
+            Vault::delete_recipient(&conn, "VAULT"),
+            Err(DmError::RecipientIsVaultKey(_))
+        ));
+        assert!(matches!(
+            Vault::delete_recipient(&conn, "CAROL"),
+            Err(DmError::RecipientNotFound(_))
+        ));
+        assert_eq!(
+            Vault::delete_recipient(&conn, "ALICE").unwrap(),
+            ["VAULT", "BOB"]
+        );
+        assert_eq!(Vault::get_recipients(&conn).unwrap(), ["VAULT", "BOB"]);
+    }
+}